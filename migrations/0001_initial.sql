@@ -0,0 +1,15 @@
+-- The schema as it existed before migrations. IF NOT EXISTS lets databases
+-- created by older builds adopt this migration without losing their data.
+CREATE TABLE IF NOT EXISTS cows (
+    cow_name VARCHAR(50) PRIMARY KEY,
+    cow_id INTEGER UNIQUE,
+    cow_color VARCHAR(20) NOT NULL,
+    cow_age INTEGER NOT NULL,
+    cow_weight INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS chat_sessions (
+    chat_session_id INTEGER PRIMARY KEY,
+    cow_id INTEGER,
+    duration INTEGER NOT NULL,
+    FOREIGN KEY(cow_id) REFERENCES cows (cow_id)
+);
@@ -0,0 +1,16 @@
+-- Full chat transcripts. Timestamps are milliseconds since the Unix epoch, the
+-- same as the `ts` of chat protocol frames. Sessions recorded before this
+-- migration only have a duration.
+ALTER TABLE chat_sessions ADD COLUMN started_at INTEGER;
+ALTER TABLE chat_sessions ADD COLUMN ended_at INTEGER;
+ALTER TABLE chat_sessions ADD COLUMN close_reason VARCHAR(50);
+
+CREATE TABLE chat_messages (
+    chat_message_id INTEGER PRIMARY KEY,
+    chat_session_id INTEGER NOT NULL,
+    sender VARCHAR(10) NOT NULL, -- 'user' or 'cow'
+    body TEXT NOT NULL,
+    sent_at INTEGER NOT NULL,
+    FOREIGN KEY(chat_session_id) REFERENCES chat_sessions (chat_session_id)
+);
+CREATE INDEX chat_messages_by_session ON chat_messages (chat_session_id, chat_message_id);
@@ -0,0 +1,3 @@
+-- Cows that were already in the meadow become cheerful, which is how they
+-- talked before personalities existed.
+ALTER TABLE cows ADD COLUMN cow_personality VARCHAR(20) NOT NULL DEFAULT 'cheerful';
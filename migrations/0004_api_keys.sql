@@ -0,0 +1,22 @@
+-- Users and their API keys. Only a SHA-256 hash of each key is stored, so the
+-- table is no use to whoever manages to read it. Timestamps are milliseconds
+-- since the Unix epoch.
+CREATE TABLE users (
+    user_id INTEGER PRIMARY KEY,
+    user_name VARCHAR(100) NOT NULL UNIQUE,
+    is_admin BOOLEAN NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE api_keys (
+    api_key_id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    revoked_at INTEGER,
+    FOREIGN KEY(user_id) REFERENCES users (user_id)
+);
+
+-- Who chatted. Sessions from before authentication, or from a server that
+-- doesn't require it, have no user.
+ALTER TABLE chat_sessions ADD COLUMN user_id INTEGER REFERENCES users (user_id);
@@ -1,41 +1,88 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet, sync::Arc, time::{Duration, Instant},
+};
 
+use actix::Addr;
 use actix_web::{
-    error, HttpRequest, HttpResponse,
+    HttpRequest, HttpResponse,
+    http::header::CACHE_CONTROL,
 };
 use actix_web::web::{
-    Data, Json, Path, Payload,
+    self, Data, Path, Payload, ReqData,
 };
 use actix_web_actors::ws;
-use anyhow::anyhow;
+// These are drop-in replacements for the actix-web extractors of the same name
+// that also run the extracted type's validators.
+use actix_web_validator::{
+    Json, Query,
+};
+use anyhow::{Context, anyhow};
+use futures_util::stream;
 use r2d2_sqlite::{
-    rusqlite, rusqlite::named_params,
+    rusqlite,
+    rusqlite::{
+        named_params, ErrorCode, OptionalExtension, Row, ToSql, TransactionBehavior,
+    },
 };
-use rand::prelude::*;
+use rand::{
+    prelude::*, rngs::StdRng,
+};
+use tokio::sync::mpsc;
 
 // `crate` is the root of import paths for local modules.
 // Relative imports with `../` are also possible.
+use crate::api::protocol::{
+    ChatMode, JSON_PROTOCOL,
+};
+use crate::api::rooms::{
+    GetPresence, ListOccupants, RoomRegistry, SubscribePresence,
+};
 use crate::api::types::{
-    BeckonCowsRequest, CowListResponse, Cow, CowColor,
+    BeckonCowsRequest, ChatMessage, ChatMessagePageResponse, ChatSession, ChatSessionPageResponse,
+    ChatStatsResponse, CowListResponse, CowPageResponse, Cow, CowColor, CowSortField,
+    CatalogReloadResponse, DatabaseHealth, HealthResponse, ListCowsQuery, OccupantsResponse, PageQuery,
+    Personality, PoolHealth, PresenceResponse, ReadinessResponse, ReleaseCowsRequest, SortOrder, UpdateCowRequest,
 };
 use crate::api::utils::{
-    COW_NAMES, make_cow,
+    make_cow,
+};
+use crate::api::websockets::{
+    ChatServices, CowChat,
+};
+use crate::auth::{AdminOnly, User};
+use crate::catalog::{
+    Catalog, CatalogStore,
+};
+use crate::config::HealthConfig;
+use crate::db::migrations::{
+    current_schema_version, latest_schema_version,
 };
-use crate::api::websockets::CowChat;
 use crate::db::queries::{
-    CHECK_FOR_COW_QUERY, COUNT_COWS_QUERY, DISTINCT_COW_NAMES_QUERY,
-    INSERT_COW_QUERY, LIST_COWS_QUERY, MAX_COW_ID_QUERY,
+    CHAT_STATS_QUERY, CHECK_FOR_CHAT_SESSION_QUERY, CHECK_FOR_COW_QUERY, COUNT_CHAT_MESSAGES_QUERY,
+    COUNT_CHAT_SESSIONS_QUERY, COUNT_COWS_QUERY, COUNT_FILTERED_COWS_QUERY,
+    DELETE_CHAT_MESSAGES_FOR_COW_QUERY, DELETE_CHAT_SESSIONS_FOR_COW_QUERY,
+    DELETE_COW_QUERY, DISTINCT_COW_NAMES_QUERY, GET_COW_BY_ID_QUERY, GET_COW_BY_NAME_QUERY, HEALTH_CHECK_QUERY,
+    INSERT_COW_QUERY, LIST_CHAT_MESSAGES_QUERY, LIST_CHAT_SESSIONS_QUERY, LIST_COWS_QUERY,
+    MAX_COW_ID_QUERY, UPDATE_COW_QUERY,
 };
 use crate::db::types::{
     MyConn, MyPool,
 };
-use crate::errors::CowError;    
+use crate::errors::CowError;
+use crate::metrics::{
+    BECKON_ERROR, BECKON_MEADOW_FULL, BECKON_SUCCESS, Metrics,
+};
+use crate::random::RequestRng;
+
+const BECKON_ATTEMPTS: u32 = 3;
+const BECKON_RETRY_DELAY: Duration = Duration::from_millis(50);
+// How many presence events a stream client may fall behind before it is cut off.
+const PRESENCE_STREAM_BUFFER: usize = 64;
 
 // Pub(crate) is a visibility modifier.
 pub(crate) async fn count_cows_handler(db_pool: Data<MyPool>) -> Result<String, CowError> {
-    // The error handling in this application is not very consistent
-    // and probably doesn't deserve much scrutiny...
-    let conn = db_pool.get().map_err(|e| CowError::from(anyhow!(e)))?;
+    // `?` converts the r2d2 error into a CowError through its From impl.
+    let conn = db_pool.get()?;
     
     // Match expressions can do destructuring, as can several other statements.
     // Also, this match expression is the return value from this function, because
@@ -43,12 +90,12 @@ pub(crate) async fn count_cows_handler(db_pool: Data<MyPool>) -> Result<String,
     match count_cows(&conn) {
         Err(e) => {
             // Macros conventionally have names with ! in them. Macros can make up new syntax.
-            log::error!("OMIGOD {}", e);
+            log::error!("Could not count the cows: {}", e);
             Err(CowError::from(e))
         },
         // This OK arm has two purposes: convert the u32 result into a String
         // (because u32 for some reason is not considered a valid response type)
-        // and also to do a no-op implicit conversion between Result<_, anyhow::Error>
+        // and also to do an implicit conversion between Result<_, anyhow::Error>
         // and Result<_, CowError>.
         Ok(value) => {
             log::debug!("Told client there were {} cows.", value);
@@ -59,15 +106,27 @@ pub(crate) async fn count_cows_handler(db_pool: Data<MyPool>) -> Result<String,
 
 // A handler with custom request and response objects.
 pub(crate) async fn beckon_cows_handler(db_pool: Data<MyPool>,
+                                        catalog: Data<CatalogStore>,
+                                        metrics: Data<Metrics>,
+                                        random: RequestRng,
                                         req: Json<BeckonCowsRequest>)
                                         -> Result<CowListResponse, CowError> {
-    let conn = db_pool.get().map_err(|e| CowError::from(anyhow!(e)))?;
-    match beckon_cows(&conn, req) {
+    // No `?`, because not getting a connection is an outcome to count too.
+    let outcome = beckon_cows(db_pool.get_ref(), catalog.current(), req.count, random.into_inner()).await;
+    match outcome.map_err(CowError::from) {
+        // A full meadow is the client's problem, not ours.
+        Err(e @ CowError::Capacity(_)) => {
+            log::info!("Could not beckon cows: {}", e);
+            metrics.record_beckon(BECKON_MEADOW_FULL);
+            Err(e)
+        },
         Err(e) => {
-            log::error!("{}", e);
-            Err(CowError::from(e))
+            log::error!("Could not beckon cows: {}", e);
+            metrics.record_beckon(BECKON_ERROR);
+            Err(e)
         },
         Ok(cows) => {
+            metrics.record_beckon(BECKON_SUCCESS);
             let s = cows.iter().map(|c| format!("{}", c)).collect::<Vec<String>>().join(", ");
             log::debug!("Generated new cows: {}", s);
             Ok(CowListResponse { cows })
@@ -75,41 +134,324 @@ pub(crate) async fn beckon_cows_handler(db_pool: Data<MyPool>,
     }
 }
 
-pub(crate) async fn list_cows_handler(db_pool: Data<MyPool>) -> Result<CowListResponse, CowError> {
-    let conn = db_pool.get().map_err(|e| CowError::from(anyhow!(e)))?;
-    match list_cows(&conn) {
+pub(crate) async fn list_cows_handler(db_pool: Data<MyPool>,
+                                      query: Query<ListCowsQuery>)
+                                      -> Result<CowPageResponse, CowError> {
+    let conn = db_pool.get()?;
+    match list_cows(&conn, &query) {
+        Err(e) => {
+            log::error!("Could not list the cows: {}", e);
+            Err(CowError::from(e))
+        },
+        Ok((cows, total)) => {
+            log::debug!("Reporting on {} of {} matching cows to client.", cows.len(), total);
+            Ok(CowPageResponse { cows, total, offset: query.offset, limit: query.limit })
+        }
+    }
+}
+
+// Names go through capitalized(), same as in websocket_cowchat_handler, so that
+// /cows/bessie and /cows/chat/bessie agree on which cow they mean.
+pub(crate) async fn get_cow_handler(db_pool: Data<MyPool>,
+                                    path: Path<String>)
+                                    -> Result<Cow, CowError> {
+    let conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    match find_cow(&conn, &cow_name)? {
+        Some(cow) => Ok(cow),
+        None => Err(CowError::NotFound(format!("No such cow currently present: {}", cow_name))),
+    }
+}
+
+pub(crate) async fn get_cow_by_id_handler(db_pool: Data<MyPool>,
+                                          path: Path<u32>)
+                                          -> Result<Cow, CowError> {
+    let conn = db_pool.get()?;
+    let cow_id = path.into_inner();
+    match find_cow_by_id(&conn, cow_id)? {
+        Some(cow) => Ok(cow),
+        None => Err(CowError::NotFound(format!("No cow currently present with id {}", cow_id))),
+    }
+}
+
+pub(crate) async fn update_cow_handler(db_pool: Data<MyPool>,
+                                       path: Path<String>,
+                                       req: Json<UpdateCowRequest>)
+                                       -> Result<Cow, CowError> {
+    let conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    if find_cow(&conn, &cow_name)?.is_none() {
+        return Err(CowError::NotFound(format!("No such cow currently present: {}", cow_name)));
+    }
+    // The cow was there a moment ago, so if it's gone now, someone released it
+    // while we were busy. That's a conflict, not a missing resource.
+    match update_cow(&conn, &cow_name, &req)? {
+        Some(cow) => {
+            log::debug!("Updated {}.", cow);
+            Ok(cow)
+        },
+        None => Err(CowError::Conflict(format!("{} left the meadow during the update", cow_name))),
+    }
+}
+
+pub(crate) async fn release_cows_handler(_: AdminOnly,
+                                         db_pool: Data<MyPool>,
+                                         random: RequestRng,
+                                         req: Json<ReleaseCowsRequest>)
+                                         -> Result<CowListResponse, CowError> {
+    let mut conn = db_pool.get()?;
+    let req = req.into_inner();
+    // Exactly one of these is Some, the validator on ReleaseCowsRequest made sure of that.
+    let outcome = match (req.count, req.names) {
+        (Some(count), _) => release_random_cows(&mut conn, count, &mut random.into_inner()),
+        (_, names) => {
+            let names: Vec<String> = names.unwrap_or_default().iter().map(|n| capitalized(n)).collect();
+            release_cows(&mut conn, &names)
+        },
+    };
+    match outcome {
         Err(e) => {
-            log::error!("{}", e);
+            log::error!("Could not release cows: {}", e);
             Err(CowError::from(e))
         },
         Ok(cows) => {
-            log::debug!("Reporting on {} existing cows to client.", cows.len());
+            log::debug!("Released {} cows from the meadow.", cows.len());
             Ok(CowListResponse { cows })
         }
     }
 }
 
+pub(crate) async fn release_cow_handler(_: AdminOnly,
+                                        db_pool: Data<MyPool>,
+                                        path: Path<String>)
+                                        -> Result<CowListResponse, CowError> {
+    let mut conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    let cows = release_cows(&mut conn, std::slice::from_ref(&cow_name))?;
+    if cows.is_empty() {
+        Err(CowError::NotFound(format!("No such cow currently present to release: {}", cow_name)))
+    } else {
+        log::debug!("Released {} from the meadow.", cow_name);
+        Ok(CowListResponse { cows })
+    }
+}
+
+// A cow's chats, newest first. Only cows currently in the meadow have any.
+// Whose chat sessions a request may read. Admins, and everyone when auth is
+// off, may read them all (None). Other users only their own.
+fn session_owner_filter(user: Option<ReqData<User>>) -> Option<i64> {
+    user.filter(|user| !user.admin).map(|user| user.id)
+}
+
+pub(crate) async fn list_chat_sessions_handler(db_pool: Data<MyPool>,
+                                               user: Option<ReqData<User>>,
+                                               path: Path<String>,
+                                               query: Query<PageQuery>)
+                                               -> Result<ChatSessionPageResponse, CowError> {
+    let conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    if !check_for_cow(&conn, &cow_name)? {
+        return Err(CowError::NotFound(format!("No such cow currently present: {}", cow_name)));
+    }
+    let (sessions, total) = list_chat_sessions(&conn, &cow_name, session_owner_filter(user), &query)?;
+    log::debug!("Reporting on {} of {} chats with {} to client.", sessions.len(), total, cow_name);
+    Ok(ChatSessionPageResponse { cow: cow_name, sessions, total, offset: query.offset, limit: query.limit })
+}
+
+pub(crate) async fn chat_stats_handler(db_pool: Data<MyPool>,
+                                       path: Path<String>)
+                                       -> Result<ChatStatsResponse, CowError> {
+    let conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    if !check_for_cow(&conn, &cow_name)? {
+        return Err(CowError::NotFound(format!("No such cow currently present: {}", cow_name)));
+    }
+    let mut stmt = conn.prepare_cached(CHAT_STATS_QUERY)?;
+    let (sessions, total_chat_secs): (u32, u64) =
+        stmt.query_row(named_params! {":cow_name": cow_name}, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    // A cow nobody has chatted with yet averages zero rather than NaN.
+    let average_duration_secs = if sessions == 0 { 0.0 } else { total_chat_secs as f64 / f64::from(sessions) };
+    Ok(ChatStatsResponse { cow: cow_name, sessions, total_chat_secs, average_duration_secs })
+}
+
+// The transcript of one chat, oldest message first. Someone else's chat is
+// answered like a missing one, so that session ids can't be probed.
+pub(crate) async fn list_chat_messages_handler(db_pool: Data<MyPool>,
+                                               user: Option<ReqData<User>>,
+                                               path: Path<i64>,
+                                               query: Query<PageQuery>)
+                                               -> Result<ChatMessagePageResponse, CowError> {
+    let conn = db_pool.get()?;
+    let session_id = path.into_inner();
+    let params = named_params! {":chat_session_id": session_id, ":user_id": session_owner_filter(user)};
+    let exists: bool = conn.prepare_cached(CHECK_FOR_CHAT_SESSION_QUERY)?.query_row(params, |row| row.get(0))?;
+    if !exists {
+        return Err(CowError::NotFound(format!("No chat session with id {}", session_id)));
+    }
+    let (messages, total) = list_chat_messages(&conn, session_id, &query)?;
+    Ok(ChatMessagePageResponse { session_id, messages, total, offset: query.offset, limit: query.limit })
+}
+
+// Re-reads the catalog file, like SIGHUP does. A broken file leaves the current
+// catalog in place. Cows whose names are no longer in the catalog are listed,
+// since they stay in the meadow until released.
+pub(crate) async fn reload_catalog_handler(db_pool: Data<MyPool>,
+                                           catalog: Data<CatalogStore>)
+                                           -> Result<CatalogReloadResponse, CowError> {
+    let reloaded = catalog.reload()?;
+    log::info!("Reloaded catalog {} with {} names", catalog.source(), reloaded.names.len());
+    let conn = db_pool.get()?;
+    let mut cows_not_in_catalog: Vec<String> = list_current_cow_names(&conn)?.into_iter()
+        .filter(|name| !reloaded.names.contains(name))
+        .collect();
+    cows_not_in_catalog.sort();
+    Ok(CatalogReloadResponse {
+        source: catalog.source().to_string(),
+        names: reloaded.names.len(),
+        phrases: reloaded.phrases.values().flat_map(|moods| moods.values()).map(Vec::len).sum(),
+        cows_not_in_catalog,
+    })
+}
+
+pub(crate) async fn presence_handler(rooms: Data<Addr<RoomRegistry>>) -> Result<PresenceResponse, CowError> {
+    let cows = rooms.send(GetPresence).await.map_err(|e| CowError::Internal(e.into()))?;
+    Ok(PresenceResponse { cows })
+}
+
+// Server-sent events: a response that never ends, to which the room registry
+// appends an event whenever a chat starts or stops.
+pub(crate) async fn presence_stream_handler(rooms: Data<Addr<RoomRegistry>>) -> Result<HttpResponse, CowError> {
+    let (sender, mut receiver) = mpsc::channel(PRESENCE_STREAM_BUFFER);
+    rooms.send(SubscribePresence { sender }).await.map_err(|e| CowError::Internal(e.into()))?;
+    // poll_fn() turns a closure into a Stream. The stream ends when the registry
+    // drops its end of the channel, and the registry notices when the client
+    // goes away, because actix-web then drops ours.
+    let events = stream::poll_fn(move |cx| receiver.poll_recv(cx).map(|frame| frame.map(Ok::<_, CowError>)));
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(events))
+}
+
+// Who is chatting with a cow right now.
+pub(crate) async fn list_occupants_handler(db_pool: Data<MyPool>,
+                                           rooms: Data<Addr<RoomRegistry>>,
+                                           path: Path<String>)
+                                           -> Result<OccupantsResponse, CowError> {
+    let conn = db_pool.get()?;
+    let cow_name = capitalized(&path.into_inner());
+    if !check_for_cow(&conn, &cow_name)? {
+        return Err(CowError::NotFound(format!("No such cow currently present: {}", cow_name)));
+    }
+    // send() (unlike do_send()) returns a future of the actor's answer. It only
+    // fails if the registry is gone, which would be a bug.
+    let occupants = rooms.send(ListOccupants { room: cow_name.clone() }).await
+        .map_err(|e| CowError::Internal(e.into()))?;
+    Ok(OccupantsResponse { cow: cow_name, occupants })
+}
+
+// Prometheus scrapes this. The herd size and the pool's connection counts are read
+// now rather than kept up to date everywhere they change. try_get() doesn't wait:
+// if every connection is busy, the herd size keeps its last value.
+pub(crate) async fn metrics_handler(db_pool: Data<MyPool>, metrics: Data<Metrics>) -> Result<HttpResponse, CowError> {
+    if let Some(conn) = db_pool.try_get() {
+        match count_cows(&conn) {
+            Ok(count) => metrics.herd_size.set(count.into()),
+            Err(e) => log::warn!("Could not count the herd for metrics: {}", e),
+        }
+    }
+    let state = db_pool.state();
+    metrics.pool_connections.set(state.connections.into());
+    metrics.pool_idle_connections.set(state.idle_connections.into());
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.encode()?))
+}
+
+// Liveness: answering at all is the whole check.
+pub(crate) async fn healthz_handler() -> HealthResponse {
+    HealthResponse { status: "ok" }
+}
+
+// Readiness: can we get a database connection and use it, in time?
+pub(crate) async fn readyz_handler(db_pool: Data<MyPool>, health: Data<HealthConfig>) -> ReadinessResponse {
+    let timeout = health.readiness_timeout();
+    let started = Instant::now();
+    // The check blocks, so web::block() runs it on a thread pool meant for that,
+    // and the timeout gives up on it without holding up this server thread. A
+    // check that is given up on still finishes in the background.
+    let pool = db_pool.get_ref().clone();
+    let check = web::block(move || check_database(&pool, timeout));
+    let (schema_version, error) = match actix_web::rt::time::timeout(timeout, check).await {
+        Ok(Ok(Ok(version))) => (Some(version), None),
+        Ok(Ok(Err(e))) => (None, Some(format!("{:#}", e))),
+        Ok(Err(e)) => (None, Some(e.to_string())),
+        Err(_) => (None, Some(format!("No answer within {} ms", timeout.as_millis()))),
+    };
+    // state() is a snapshot, taken after the check so that it includes its connection.
+    let state = db_pool.state();
+    let response = ReadinessResponse {
+        status: "ready",
+        database: DatabaseHealth {
+            ok: error.is_none(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            error,
+        },
+        pool: PoolHealth { connections: state.connections, idle: state.idle_connections, max_size: db_pool.max_size() },
+        schema_version,
+        expected_schema_version: latest_schema_version(),
+    };
+    if response.is_ready() {
+        response
+    } else {
+        log::warn!("Not ready: {:?}", response);
+        ReadinessResponse { status: "unavailable", ..response }
+    }
+}
+
 pub(crate) async fn websocket_cowchat_handler(db_pool: Data<MyPool>,
+                                              services: Data<ChatServices>,
+                                              random: RequestRng,
+                                              user: Option<ReqData<User>>,
                                               path: Path<String>,
                                               req: HttpRequest,
                                               stream: Payload)
-                                              -> Result<HttpResponse, error::Error> {
-    // Sometimes inference fails and you need to manually dereference/reborrow some value to get it to work.
-    let pool_ref = (*db_pool).clone();
+                                              -> Result<HttpResponse, CowError> {
+    if services.draining.is_draining() {
+        return Err(CowError::ShuttingDown("The server is shutting down and takes no new chats".to_string()));
+    }
     let cow_name = capitalized(&path.into_inner());
-    let conn = db_pool.get().map_err(error::ErrorInternalServerError)?;
-    if check_for_cow(&conn, &cow_name).map_err(error::ErrorInternalServerError)? {
-        // The websocket module handles the handshake and socket setup.
-        ws::start(CowChat::new(pool_ref, &cow_name), &req, stream)
+    let conn = db_pool.get()?;
+    if let Some(cow) = find_cow(&conn, &cow_name)? {
+        // The websocket module handles the handshake and socket setup. It fails
+        // when the request isn't a valid websocket upgrade. If the client asked
+        // for the JSON protocol, the handshake response confirms it.
+        let mode = ChatMode::negotiate(&req);
+        let user = user.map(ReqData::into_inner);
+        let actor = CowChat::new(&services, &cow, user, mode, random.into_inner());
+        ws::WsResponseBuilder::new(actor, &req, stream)
+            .protocols(&[JSON_PROTOCOL])
+            .start()
+            .map_err(|e| CowError::BadRequest(e.to_string()))
     } else {
-        Err(error::ErrorBadRequest(anyhow!("No such cow currently present to chat with: {}", cow_name)))
+        Err(CowError::NotFound(format!("No such cow currently present to chat with: {}", cow_name)))
     }
 }
 
+// Returns the schema version, which the readiness check wants anyway.
+fn check_database(pool: &MyPool, timeout: Duration) -> anyhow::Result<u32> {
+    let conn = pool.get_timeout(timeout)?;
+    conn.prepare_cached(HEALTH_CHECK_QUERY)?.query_row([], |row| row.get::<_, i64>(0))?;
+    current_schema_version(&conn)
+}
+
 fn capitalized(s: &str) -> String {
     let mut cs = s.chars();
-    // First character capitalized + rest of string.
-    cs.next().unwrap().to_uppercase().chain(cs).collect()
+    // First character capitalized + rest of string. An empty string stays empty.
+    match cs.next() {
+        Some(first) => first.to_uppercase().chain(cs).collect(),
+        None => String::new(),
+    }
 }
 
 fn check_for_cow(conn: &MyConn, cow_name: &str) -> Result<bool, CowError> {
@@ -125,10 +467,10 @@ fn check_for_cow(conn: &MyConn, cow_name: &str) -> Result<bool, CowError> {
     });
     // Sadly, SQLite doesn't have booleans, only 0 and 1. In this case, 1 means
     // that a given cow is present in the DB.
-    row.map(|val| val == 1).map_err(|e| CowError::from(anyhow!(e)))
+    row.map(|val| val == 1).map_err(CowError::from)
 }
 
-fn count_cows(conn: &MyConn) -> anyhow::Result<u32> {
+fn count_cows(conn: &rusqlite::Connection) -> anyhow::Result<u32> {
     let mut stmt = conn.prepare_cached(COUNT_COWS_QUERY)?;
     let mut rows = stmt.query([])?; // this query takes no params
     let row = rows.next()?.ok_or_else(|| anyhow!("COUNT returned no rows!"))?;
@@ -138,7 +480,7 @@ fn count_cows(conn: &MyConn) -> anyhow::Result<u32> {
     Ok(count)
 }
 
-fn list_current_cow_names(conn: &MyConn) -> anyhow::Result<HashSet<String>> {
+fn list_current_cow_names(conn: &rusqlite::Connection) -> anyhow::Result<HashSet<String>> {
     let mut stmt = conn.prepare_cached(DISTINCT_COW_NAMES_QUERY)?;
     let used_names: HashSet<String> = stmt.query_map([], |row| row.get(0))?
         // Where generic types can be inferred, they can be replaced with `_`.
@@ -149,21 +491,124 @@ fn list_current_cow_names(conn: &MyConn) -> anyhow::Result<HashSet<String>> {
     Ok(used_names)
 }
 
-fn list_cows(conn: &MyConn) -> anyhow::Result<Vec<Cow>> {
-    let mut stmt = conn.prepare_cached(LIST_COWS_QUERY)?;
+// Maps a `SELECT * FROM cows` row to a Cow. Function items can be passed
+// anywhere a closure of the same signature is expected.
+fn cow_from_row(row: &Row<'_>) -> rusqlite::Result<Cow> {
+    let name: String = row.get_unwrap(0);
+    let id: u32 = row.get_unwrap(1);
+    let color: CowColor = row.get_unwrap(2);
+    let age: u32 = row.get_unwrap(3);
+    let weight: u32 = row.get_unwrap(4);
+    let personality: Personality = row.get_unwrap(5);
+    Ok(Cow::new(name.as_str(), id, color, age, weight, personality))
+}
+
+// Named query parameters whose values are only known at runtime.
+// `Box<dyn ToSql>` lets values of different types live in the same Vec.
+type DynParams = Vec<(&'static str, Box<dyn ToSql>)>;
+
+// Builds the WHERE clause for a ListCowsQuery, along with the values to bind.
+fn cow_filter_clause(query: &ListCowsQuery) -> anyhow::Result<(String, DynParams)> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut params: DynParams = Vec::new();
+    if let Some(color) = &query.color {
+        conditions.push("cow_color = :color");
+        params.push((":color", Box::new(CowColor::try_from(color.as_str())?)));
+    }
+    if let Some(min_age) = query.min_age {
+        conditions.push("cow_age >= :min_age");
+        params.push((":min_age", Box::new(min_age)));
+    }
+    if let Some(max_age) = query.max_age {
+        conditions.push("cow_age <= :max_age");
+        params.push((":max_age", Box::new(max_age)));
+    }
+    if let Some(min_weight) = query.min_weight {
+        conditions.push("cow_weight >= :min_weight");
+        params.push((":min_weight", Box::new(min_weight)));
+    }
+    if let Some(max_weight) = query.max_weight {
+        conditions.push("cow_weight <= :max_weight");
+        params.push((":max_weight", Box::new(max_weight)));
+    }
+    if let Some(prefix) = &query.name_prefix {
+        // LIKE wildcards in the prefix itself have to be escaped to match literally.
+        let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        conditions.push("cow_name LIKE :name_prefix ESCAPE '\\'");
+        params.push((":name_prefix", Box::new(format!("{}%", escaped))));
+    }
+    let clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    };
+    Ok((clause, params))
+}
+
+fn list_cows(conn: &MyConn, query: &ListCowsQuery) -> anyhow::Result<(Vec<Cow>, u32)> {
+    let (where_clause, mut params) = cow_filter_clause(query)?;
+
+    // The params are borrowed into the slice-of-tuples shape that rusqlite accepts.
+    let count_sql = format!("{}{};", COUNT_FILTERED_COWS_QUERY, where_clause);
+    let count_params: Vec<(&str, &dyn ToSql)> = params.iter().map(|(k, v)| (*k, v.as_ref())).collect();
+    let total: u32 = conn.prepare_cached(&count_sql)?.query_row(count_params.as_slice(), |row| row.get(0))?;
+
+    // Column names come from a fixed match, never from user input, so splicing them in is safe.
+    let sort_column = match query.sort {
+        CowSortField::Name => "cow_name",
+        CowSortField::Id => "cow_id",
+        CowSortField::Color => "cow_color",
+        CowSortField::Age => "cow_age",
+        CowSortField::Weight => "cow_weight",
+    };
+    let direction = match query.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    // SQLite treats a negative LIMIT as "no limit". Ties are broken by id so pages are stable.
+    params.push((":limit", Box::new(query.limit.map(i64::from).unwrap_or(-1))));
+    params.push((":offset", Box::new(query.offset)));
+    let list_sql = format!("{}{} ORDER BY {} {}, cow_id ASC LIMIT :limit OFFSET :offset;",
+                           LIST_COWS_QUERY, where_clause, sort_column, direction);
+    let list_params: Vec<(&str, &dyn ToSql)> = params.iter().map(|(k, v)| (*k, v.as_ref())).collect();
+    let mut stmt = conn.prepare_cached(&list_sql)?;
     // query_map() maps a function over the list of returned rows.
-    let cows: Vec<Cow> = stmt.query_map([], |row| {
-        let name: String = row.get_unwrap(0);
-        let id: u32 = row.get_unwrap(1);
-        let color: CowColor = row.get_unwrap(2);
-        let age: u32 = row.get_unwrap(3);
-        let weight: u32 = row.get_unwrap(4);
-        Ok(Cow::new(name.as_str(), id, color, age, weight))
-    })?.map(|x: Result<Cow, _>| x.unwrap()).collect();
-    Ok(cows)
-}
-
-fn get_current_max_id(conn: &MyConn) -> anyhow::Result<u32> {
+    let cows: Vec<Cow> = stmt.query_map(list_params.as_slice(), cow_from_row)?
+        .map(|x: Result<Cow, _>| x.unwrap())
+        .collect();
+    Ok((cows, total))
+}
+
+fn find_cow(conn: &rusqlite::Connection, cow_name: &str) -> anyhow::Result<Option<Cow>> {
+    let mut stmt = conn.prepare_cached(GET_COW_BY_NAME_QUERY)?;
+    // optional() turns the "no rows" error into Ok(None).
+    let cow = stmt.query_row(named_params! {":cow_name": cow_name}, cow_from_row).optional()?;
+    Ok(cow)
+}
+
+// Returns the updated cow, or None if there was no cow to update.
+fn update_cow(conn: &rusqlite::Connection, cow_name: &str, req: &UpdateCowRequest) -> anyhow::Result<Option<Cow>> {
+    // The color was validated already, so conversion failure is unexpected here.
+    let color = req.color.as_deref().map(CowColor::try_from).transpose()?;
+    let changed = conn.prepare_cached(UPDATE_COW_QUERY)?.execute(named_params! {
+        ":cow_name": cow_name,
+        ":cow_color": color,
+        ":cow_age": req.age,
+        ":cow_weight": req.weight,
+    })?;
+    if changed == 0 {
+        return Ok(None);
+    }
+    find_cow(conn, cow_name)
+}
+
+fn find_cow_by_id(conn: &rusqlite::Connection, cow_id: u32) -> anyhow::Result<Option<Cow>> {
+    let mut stmt = conn.prepare_cached(GET_COW_BY_ID_QUERY)?;
+    let cow = stmt.query_row(named_params! {":cow_id": cow_id}, cow_from_row).optional()?;
+    Ok(cow)
+}
+
+fn get_current_max_id(conn: &rusqlite::Connection) -> anyhow::Result<u32> {
     let mut stmt = conn.prepare_cached(MAX_COW_ID_QUERY)?;
     let max_id: u32 = stmt.query([])?
                           .next()?
@@ -172,41 +617,171 @@ fn get_current_max_id(conn: &MyConn) -> anyhow::Result<u32> {
     Ok(max_id)
 }
 
-fn write_cows(conn: &MyConn, cows: &Vec<Cow>) -> anyhow::Result<()> {
+fn write_cows(conn: &rusqlite::Connection, cows: &Vec<Cow>) -> anyhow::Result<()> {
     let mut stmt = conn.prepare_cached(INSERT_COW_QUERY)?;
     for cow in cows {
         // Destructing assignment. This works because the felds of Cow are public.
-        let Cow { id, name, color, age, weight} = cow;
+        let Cow { id, name, color, age, weight, personality } = cow;
         stmt.execute(named_params! {
             ":cow_name": name,
             ":cow_id": id,
             ":cow_color": color,
             ":cow_age": age,
             ":cow_weight": weight,
+            ":cow_personality": personality,
         })?;
     }
     Ok(())
 }
 
-fn beckon_cows(conn: &MyConn, req: Json<BeckonCowsRequest>) -> anyhow::Result<Vec<Cow>> {
-    let mut random = rand::thread_rng();
-    let desired_number = req.count;
-    let max_cows = COW_NAMES.len() as u32;
-    let current_cows = count_cows(conn)?;
-    let adjusted_number = desired_number.min(max_cows - current_cows);
+// Beckoning reads the current herd and then writes to it, so it runs inside an
+// IMMEDIATE transaction, which takes the database write lock up front. Concurrent
+// beckons are serialized instead of picking the same names or ids, and a failure
+// rolls back every cow of the request. SQLite reports contention as SQLITE_BUSY
+// once its own busy timeout runs out, in which case we start over a few times.
+// Every attempt starts from a copy of the same generator, so the cows we end up
+// with only depend on its seed, not on how often we had to try.
+//
+// Attempts block while SQLite waits, so web::block() runs them on the thread pool
+// meant for that, and the pause between them is an async sleep. Either way, the
+// server thread goes on with other requests in the meantime.
+async fn beckon_cows(db_pool: &MyPool,
+                     catalog: Arc<Catalog>,
+                     desired_number: u32,
+                     random: StdRng)
+                     -> anyhow::Result<Vec<Cow>> {
+    let mut attempt = 1;
+    loop {
+        // The closure must own what it uses, so every attempt gets its own copies.
+        let (pool, catalog, mut random) = (db_pool.clone(), catalog.clone(), random.clone());
+        let outcome = web::block(move || {
+            let mut conn = pool.get().map_err(CowError::from)?;
+            try_beckon_cows(&mut conn, &catalog, desired_number, &mut random)
+        }).await?;
+        match outcome {
+            Err(e) if is_busy(&e) && attempt < BECKON_ATTEMPTS => {
+                log::warn!("Meadow busy on beckon attempt {}, retrying...", attempt);
+                actix_web::rt::time::sleep(BECKON_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            },
+            outcome => return outcome,
+        }
+    }
+}
+
+// The meadow is full once every name in the catalog is taken. Cows whose names
+// were dropped from the catalog after they arrived stay until they are released,
+// but don't take up any of the catalog's places.
+fn try_beckon_cows(conn: &mut MyConn,
+                   catalog: &Catalog,
+                   desired_number: u32,
+                   random: &mut StdRng)
+                   -> anyhow::Result<Vec<Cow>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let used_names = list_current_cow_names(&tx)?;
+    let available_names: Vec<&String> = catalog.names.iter().filter(|name| !used_names.contains(*name)).collect();
+    let adjusted_number = desired_number.min(available_names.len() as u32);
     if adjusted_number == 0 {
-        anyhow::bail!("Insufficient cows in meadow! Let some go!")
+        return Err(CowError::Capacity("Insufficient cows in meadow! Let some go!".to_string()).into());
     }
-    let used_names = list_current_cow_names(conn)?;
-    let chosen_available_names = COW_NAMES.difference(&used_names)
-        .into_iter()
-        .choose_multiple(&mut random, adjusted_number as usize);
-    let max_id = get_current_max_id(conn)?;
+    let chosen_available_names = available_names.into_iter()
+        .choose_multiple(random, adjusted_number as usize);
+    let max_id = get_current_max_id(&tx)?;
     let new_cows: Vec<Cow> = chosen_available_names.iter().enumerate().map(|(index, name)| {
         let next_available_id = max_id + index as u32 + 1;
-        make_cow(name, next_available_id)
+        make_cow(name, next_available_id, random)
     }).collect();
-    let write_outcome = write_cows(conn, &new_cows);
-    write_outcome.map_err(|e| anyhow!("Could not write cows to database: {}", e))?;
+    // context() keeps the original error inside, so is_busy() can still see it.
+    write_cows(&tx, &new_cows).context("Could not write cows to database")?;
+    // Dropping a Transaction without committing rolls it back, which is what
+    // happens on every early return above.
+    tx.commit()?;
     Ok(new_cows)
 }
+
+fn is_busy(e: &anyhow::Error) -> bool {
+    // downcast_ref() peeks at the concrete error type hidden inside an anyhow::Error.
+    matches!(
+        e.downcast_ref::<rusqlite::Error>(),
+        Some(rusqlite::Error::SqliteFailure(err, _)) if err.code == ErrorCode::DatabaseBusy
+    )
+}
+
+// Releases whichever of the named cows are present and returns them. Names of
+// absent cows are skipped. Everything happens in one transaction, so a failure
+// partway through leaves the meadow as it was.
+fn release_cows(conn: &mut MyConn, names: &[String]) -> anyhow::Result<Vec<Cow>> {
+    let tx = conn.transaction()?;
+    let mut released = Vec::new();
+    for name in names {
+        // A Transaction derefs to a Connection, so the usual helpers work on it.
+        if let Some(cow) = find_cow(&tx, name)? {
+            tx.prepare_cached(DELETE_CHAT_MESSAGES_FOR_COW_QUERY)?
+              .execute(named_params! {":cow_name": name})?;
+            tx.prepare_cached(DELETE_CHAT_SESSIONS_FOR_COW_QUERY)?
+              .execute(named_params! {":cow_name": name})?;
+            tx.prepare_cached(DELETE_COW_QUERY)?
+              .execute(named_params! {":cow_name": name})?;
+            released.push(cow);
+        }
+    }
+    tx.commit()?;
+    Ok(released)
+}
+
+fn release_random_cows(conn: &mut MyConn, count: u32, random: &mut StdRng) -> anyhow::Result<Vec<Cow>> {
+    let current_names = list_current_cow_names(conn)?;
+    if current_names.is_empty() {
+        return Err(CowError::Conflict("No cows in meadow to release!".to_string()).into());
+    }
+    // A HashSet iterates in a different order every run, so the names are sorted
+    // first. Otherwise the same seed could pick different cows.
+    let mut current_names: Vec<String> = current_names.into_iter().collect();
+    current_names.sort();
+    let chosen: Vec<String> = current_names.into_iter().choose_multiple(random, count as usize);
+    release_cows(conn, &chosen)
+}
+
+fn list_chat_sessions(conn: &MyConn,
+                      cow_name: &str,
+                      user_id: Option<i64>,
+                      page: &PageQuery)
+                      -> anyhow::Result<(Vec<ChatSession>, u32)> {
+    let total: u32 = conn.prepare_cached(COUNT_CHAT_SESSIONS_QUERY)?
+        .query_row(named_params! {":cow_name": cow_name, ":user_id": user_id}, |row| row.get(0))?;
+    let mut stmt = conn.prepare_cached(LIST_CHAT_SESSIONS_QUERY)?;
+    let params = named_params! {
+        ":cow_name": cow_name,
+        ":user_id": user_id,
+        ":limit": page.limit.map(i64::from).unwrap_or(-1),
+        ":offset": page.offset,
+    };
+    let sessions = stmt.query_map(params, |row| {
+        Ok(ChatSession {
+            id: row.get(0)?,
+            started_at: row.get(1)?,
+            ended_at: row.get(2)?,
+            duration_secs: row.get(3)?,
+            close_reason: row.get(4)?,
+            message_count: row.get(5)?,
+            user_id: row.get(6)?,
+        })
+    })?.collect::<Result<Vec<ChatSession>, _>>()?;
+    Ok((sessions, total))
+}
+
+fn list_chat_messages(conn: &MyConn, session_id: i64, page: &PageQuery) -> anyhow::Result<(Vec<ChatMessage>, u32)> {
+    let total: u32 = conn.prepare_cached(COUNT_CHAT_MESSAGES_QUERY)?
+        .query_row(named_params! {":chat_session_id": session_id}, |row| row.get(0))?;
+    let mut stmt = conn.prepare_cached(LIST_CHAT_MESSAGES_QUERY)?;
+    let params = named_params! {
+        ":chat_session_id": session_id,
+        ":limit": page.limit.map(i64::from).unwrap_or(-1),
+        ":offset": page.offset,
+    };
+    // Collecting into a Result stops at the first row that fails to convert.
+    let messages = stmt.query_map(params, |row| {
+        Ok(ChatMessage { id: row.get(0)?, sender: row.get(1)?, body: row.get(2)?, sent_at: row.get(3)? })
+    })?.collect::<Result<Vec<ChatMessage>, _>>()?;
+    Ok((messages, total))
+}
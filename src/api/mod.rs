@@ -2,6 +2,8 @@
 // parent module). The other files in this directory are child modules of the
 // api module.
 pub(crate) mod handlers;
+pub(crate) mod protocol;
+pub(crate) mod rooms;
 pub(crate) mod types;
 pub(crate) mod utils;
 pub(crate) mod websockets;
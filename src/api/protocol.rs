@@ -0,0 +1,141 @@
+use actix_web::{
+    HttpRequest, http::header::SEC_WEBSOCKET_PROTOCOL,
+};
+use serde::{
+    Deserialize, Serialize,
+};
+use serde_json::{json, Value};
+
+use crate::api::utils::unix_millis;
+
+// The structured chat protocol. Clients opt in by asking for this subprotocol
+// in the Sec-WebSocket-Protocol header of the upgrade request. The version is part
+// of the name, so an incompatible v2 can be negotiated side by side later.
+// Clients that don't ask for it get the original plain-text chat.
+pub(crate) const JSON_PROTOCOL: &str = "cowchat.v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChatMode {
+    PlainText,
+    Json,
+}
+
+impl ChatMode {
+    // Mirrors what actix-web-actors does when it picks the protocol for the
+    // handshake response, so the actor and the client agree on the mode.
+    pub fn negotiate(req: &HttpRequest) -> Self {
+        let requested = req.headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(',').any(|p| p.trim() == JSON_PROTOCOL))
+            .unwrap_or(false);
+        if requested { ChatMode::Json } else { ChatMode::PlainText }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FrameKind {
+    // A chat message. From the client, `body` is the text. From the server, it is
+    // `{"from": <cow>, "text": <text>}`.
+    Say,
+    // The client is typing. Needs no body and gets no answer.
+    Typing,
+    // The server received a client frame. `body` is `{"ref": <client frame id>}`.
+    Ack,
+    // Something was wrong with a client frame. `body` is `{"code", "message", "ref"}`.
+    Error,
+    // Notices from the server itself, like the greeting. `body` is the text.
+    System,
+}
+
+// Every frame in either direction has this shape. Clients pick their own ids,
+// which the server echoes back in acks and errors. `ts` is milliseconds since the
+// Unix epoch and is optional from clients.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Envelope {
+    #[serde(rename = "type")]
+    pub kind: FrameKind,
+    pub id: String,
+    #[serde(default)]
+    pub body: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+}
+
+// A client frame that made sense.
+pub(crate) enum Inbound {
+    Say { id: String, text: String },
+    Typing,
+}
+
+// A client frame that didn't, with what to tell the client about it.
+pub(crate) struct FrameError {
+    pub code: &'static str,
+    pub message: String,
+    // The id of the offending frame, if it got far enough to have one.
+    pub ref_id: Option<String>,
+}
+
+impl FrameError {
+    pub fn new(code: &'static str, message: impl Into<String>, ref_id: Option<String>) -> Self {
+        Self { code, message: message.into(), ref_id }
+    }
+}
+
+pub(crate) fn parse_inbound(text: &str) -> Result<Inbound, FrameError> {
+    // Parsing into a generic Value first lets us tell apart broken JSON, unknown
+    // types and bad fields, instead of getting one opaque serde error for all three.
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| FrameError::new("malformed_frame", format!("Frame is not valid JSON: {}", e), None))?;
+    let ref_id = value.get("id").and_then(Value::as_str).map(String::from);
+    let kind = value.get("type")
+        .ok_or_else(|| FrameError::new("malformed_frame", "Frame has no \"type\"", ref_id.clone()))?
+        .clone();
+    if serde_json::from_value::<FrameKind>(kind.clone()).is_err() {
+        return Err(FrameError::new("unknown_type", format!("Unknown frame type {}", kind), ref_id));
+    }
+    let envelope: Envelope = serde_json::from_value(value)
+        .map_err(|e| FrameError::new("malformed_frame", e.to_string(), ref_id.clone()))?;
+    match envelope.kind {
+        FrameKind::Say => match envelope.body {
+            Value::String(text) if !text.trim().is_empty() => Ok(Inbound::Say { id: envelope.id, text }),
+            _ => Err(FrameError::new("malformed_frame", "A say frame needs a non-empty string body", ref_id)),
+        },
+        FrameKind::Typing => Ok(Inbound::Typing),
+        FrameKind::Ack | FrameKind::Error | FrameKind::System => {
+            Err(FrameError::new("unknown_type", format!("Clients can't send frames of type {}", kind), ref_id))
+        },
+    }
+}
+
+// Hands out ids and timestamps for server frames. One per chat session.
+#[derive(Default)]
+pub(crate) struct FrameFactory {
+    next_id: u64,
+}
+
+impl FrameFactory {
+    fn envelope(&mut self, kind: FrameKind, body: Value) -> String {
+        self.next_id += 1;
+        let envelope = Envelope { kind, id: format!("s{}", self.next_id), body, ts: Some(unix_millis()) };
+        // Serializing our own struct can't fail.
+        serde_json::to_string(&envelope).unwrap()
+    }
+
+    pub fn say(&mut self, from: &str, text: &str) -> String {
+        self.envelope(FrameKind::Say, json!({"from": from, "text": text}))
+    }
+
+    pub fn ack(&mut self, ref_id: &str) -> String {
+        self.envelope(FrameKind::Ack, json!({"ref": ref_id}))
+    }
+
+    pub fn error(&mut self, error: &FrameError) -> String {
+        self.envelope(FrameKind::Error, json!({"code": error.code, "message": error.message, "ref": error.ref_id}))
+    }
+
+    pub fn system(&mut self, text: &str) -> String {
+        self.envelope(FrameKind::System, Value::String(text.to_string()))
+    }
+}
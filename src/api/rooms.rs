@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap, time::Duration,
+};
+
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::api::utils::unix_millis;
+
+// Everyone chatting with the same cow shares a room. The registry is a single
+// actor that knows who is in which room, and every chat goes through it to reach
+// the others. Since an actor handles one message at a time, joins, leaves and
+// broadcasts can't trip over each other, and no locks are needed.
+//
+// Rooms are named after their cow. A room exists for as long as somebody is in it.
+//
+// Since the registry sees every chat start and stop, it also keeps the presence
+// stream subscribers informed. They get server-sent events: text frames of the
+// form "event: <name>\ndata: <json>\n\n".
+#[derive(Default)]
+pub(crate) struct RoomRegistry {
+    rooms: HashMap<String, HashMap<u64, Member>>,
+    presence_subscribers: Vec<mpsc::Sender<Bytes>>,
+    // Set by CloseAll, when the server is shutting down.
+    closed: bool,
+}
+
+// Proxies tend to cut connections that stay quiet for too long, so presence
+// streams get an SSE comment line at this interval, changes or not.
+const PRESENCE_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+struct Member {
+    name: String,
+    joined_at: u64,
+    last_heartbeat: u64,
+    // A Recipient is like an Addr, but only knows about one message type, so the
+    // registry doesn't have to know what kind of actor is on the other end.
+    recipient: Recipient<RoomEvent>,
+}
+
+// What chats hear from the registry about the rest of their room.
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub(crate) enum RoomEvent {
+    Joined { name: String },
+    Left { name: String },
+    // Someone else said something to the cow.
+    Said { name: String, text: String },
+    // The cow answered someone else.
+    CowSaid { text: String },
+    // Not about the room at all: the server is shutting down, and the chat has to end.
+    ServerShutdown,
+}
+
+// Chats identify themselves by the session key they already use for transcripts.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct Join {
+    pub room: String,
+    pub key: u64,
+    pub name: String,
+    pub recipient: Recipient<RoomEvent>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct Leave {
+    pub room: String,
+    pub key: u64,
+}
+
+// Passes the event on to everyone in the room except the chat it came from,
+// which has already dealt with it itself.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct Broadcast {
+    pub room: String,
+    pub from_key: u64,
+    pub event: RoomEvent,
+}
+
+// Chats report every ping or pong from their client.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct Heartbeat {
+    pub room: String,
+    pub key: u64,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<CowPresence>")]
+pub(crate) struct GetPresence;
+
+// The subscriber first gets a "snapshot" event with the presence of every cow
+// that is being chatted with, then a "presence" event for a cow whenever one of
+// its chats starts or stops. Dropping the receiving end unsubscribes.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct SubscribePresence {
+    pub sender: mpsc::Sender<Bytes>,
+}
+
+// Sent when the server shuts down. Tells every chat to end and ends every
+// presence stream, and answers how many chats there were. Chats that join and
+// presence streams that subscribe afterwards end right away.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub(crate) struct CloseAll;
+
+#[derive(Message)]
+#[rtype(result = "Vec<Occupant>")]
+pub(crate) struct ListOccupants {
+    pub room: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Occupant {
+    pub id: u64,
+    pub name: String,
+    pub joined_at: u64,
+}
+
+// Open chats with one cow. Timestamps are milliseconds since the Unix epoch.
+// A cow whose last chat just ended has zero sessions and no heartbeat.
+#[derive(Debug, Serialize)]
+pub(crate) struct CowPresence {
+    pub cow: String,
+    pub sessions: usize,
+    pub oldest_session_secs: u64,
+    pub last_heartbeat_at: Option<u64>,
+}
+
+impl RoomRegistry {
+    fn presence_of(&self, room: &str) -> CowPresence {
+        let members = self.rooms.get(room);
+        let now = unix_millis();
+        let oldest = members.and_then(|m| m.values().map(|member| member.joined_at).min());
+        CowPresence {
+            cow: room.to_string(),
+            sessions: members.map(HashMap::len).unwrap_or(0),
+            oldest_session_secs: oldest.map(|joined_at| now.saturating_sub(joined_at) / 1000).unwrap_or(0),
+            last_heartbeat_at: members.and_then(|m| m.values().map(|member| member.last_heartbeat).max()),
+        }
+    }
+
+    fn presence(&self) -> Vec<CowPresence> {
+        let mut cows: Vec<CowPresence> = self.rooms.keys().map(|room| self.presence_of(room)).collect();
+        cows.sort_by(|a, b| a.cow.cmp(&b.cow));
+        cows
+    }
+
+    // retain() keeps only the elements the closure returns true for. A subscriber
+    // that has gone away, or has fallen so far behind that its buffer is full, is
+    // dropped. Its client can reconnect and start over from a snapshot.
+    fn publish(&mut self, frame: Bytes) {
+        self.presence_subscribers.retain(|sender| sender.try_send(frame.clone()).is_ok());
+    }
+
+    fn publish_presence_of(&mut self, room: &str) {
+        let frame = sse_event("presence", &self.presence_of(room));
+        self.publish(frame);
+    }
+
+    fn send_to_room(&self, room: &str, except: u64, event: RoomEvent) {
+        let Some(members) = self.rooms.get(room) else { return };
+        for (key, member) in members {
+            if *key != except {
+                // do_send() drops the event if that chat is already gone.
+                member.recipient.do_send(event.clone());
+            }
+        }
+    }
+}
+
+fn sse_event<T: Serialize>(event: &str, data: &T) -> Bytes {
+    // Serializing our own structs can't fail, and JSON never contains a raw newline.
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, serde_json::to_string(data).unwrap()))
+}
+
+impl Actor for RoomRegistry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        context.run_interval(PRESENCE_KEEPALIVE_INTERVAL, |registry, _| {
+            registry.publish(Bytes::from_static(b": keep-alive\n\n"));
+        });
+    }
+}
+
+impl Handler<Join> for RoomRegistry {
+    type Result = ();
+
+    fn handle(&mut self, join: Join, _: &mut Self::Context) {
+        // A chat that got past the draining check just before shutdown began can
+        // join after CloseAll. It is told to end right away, like the others were,
+        // instead of being left for the HTTP server to drop unrecorded.
+        if self.closed {
+            join.recipient.do_send(RoomEvent::ServerShutdown);
+            return;
+        }
+        log::debug!("{} joined the room of {}", join.name, join.room);
+        self.send_to_room(&join.room, join.key, RoomEvent::Joined { name: join.name.clone() });
+        let now = unix_millis();
+        let member = Member { name: join.name, joined_at: now, last_heartbeat: now, recipient: join.recipient };
+        // entry() looks up a key and, if it's missing, inserts the value we give it.
+        self.rooms.entry(join.room.clone()).or_default().insert(join.key, member);
+        self.publish_presence_of(&join.room);
+    }
+}
+
+impl Handler<Leave> for RoomRegistry {
+    type Result = ();
+
+    fn handle(&mut self, leave: Leave, _: &mut Self::Context) {
+        let Some(members) = self.rooms.get_mut(&leave.room) else { return };
+        let Some(member) = members.remove(&leave.key) else { return };
+        log::debug!("{} left the room of {}", member.name, leave.room);
+        if members.is_empty() {
+            self.rooms.remove(&leave.room);
+        } else {
+            self.send_to_room(&leave.room, leave.key, RoomEvent::Left { name: member.name });
+        }
+        self.publish_presence_of(&leave.room);
+    }
+}
+
+impl Handler<Broadcast> for RoomRegistry {
+    type Result = ();
+
+    fn handle(&mut self, broadcast: Broadcast, _: &mut Self::Context) {
+        self.send_to_room(&broadcast.room, broadcast.from_key, broadcast.event);
+    }
+}
+
+impl Handler<Heartbeat> for RoomRegistry {
+    type Result = ();
+
+    fn handle(&mut self, heartbeat: Heartbeat, _: &mut Self::Context) {
+        let member = self.rooms.get_mut(&heartbeat.room).and_then(|members| members.get_mut(&heartbeat.key));
+        if let Some(member) = member {
+            member.last_heartbeat = unix_millis();
+        }
+    }
+}
+
+impl Handler<GetPresence> for RoomRegistry {
+    type Result = MessageResult<GetPresence>;
+
+    fn handle(&mut self, _: GetPresence, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.presence())
+    }
+}
+
+impl Handler<SubscribePresence> for RoomRegistry {
+    type Result = ();
+
+    fn handle(&mut self, subscribe: SubscribePresence, _: &mut Self::Context) {
+        if self.closed {
+            return;
+        }
+        let snapshot = sse_event("snapshot", &serde_json::json!({"cows": self.presence()}));
+        if subscribe.sender.try_send(snapshot).is_ok() {
+            self.presence_subscribers.push(subscribe.sender);
+        }
+    }
+}
+
+impl Handler<CloseAll> for RoomRegistry {
+    type Result = usize;
+
+    // The chats leave their rooms by themselves as they stop.
+    fn handle(&mut self, _: CloseAll, _: &mut Self::Context) -> usize {
+        self.closed = true;
+        // Dropping the senders ends the streams.
+        self.presence_subscribers.clear();
+        let members: Vec<&Member> = self.rooms.values().flat_map(HashMap::values).collect();
+        for member in &members {
+            member.recipient.do_send(RoomEvent::ServerShutdown);
+        }
+        members.len()
+    }
+}
+
+impl Handler<ListOccupants> for RoomRegistry {
+    // MessageResult wraps plain values, which Handler results otherwise can't be.
+    type Result = MessageResult<ListOccupants>;
+
+    fn handle(&mut self, list: ListOccupants, _: &mut Self::Context) -> Self::Result {
+        let mut occupants: Vec<Occupant> = self.rooms.get(&list.room)
+            .map(|members| members.iter().map(|(key, member)| {
+                Occupant { id: *key, name: member.name.clone(), joined_at: member.joined_at }
+            }).collect())
+            .unwrap_or_default();
+        occupants.sort_by_key(|occupant| occupant.id);
+        MessageResult(occupants)
+    }
+}
@@ -4,6 +4,7 @@ use std::fmt::{
 
 use actix_web::{
     body::BoxBody, HttpRequest, HttpResponse, Responder,
+    http::StatusCode,
 };
 use r2d2_sqlite::{
     rusqlite,
@@ -15,7 +16,11 @@ use r2d2_sqlite::{
 use serde::{
     Deserialize, Serialize,
 };
-use validator::Validate;
+use validator::{Validate, ValidationError};
+
+use crate::api::rooms::{
+    CowPresence, Occupant,
+};
 
 // Derive directives create minimal automatic implementations of certain fundamental traits.
 // Deserialize is about unmarshalling values from JSON sent over the wire.
@@ -25,10 +30,135 @@ pub(crate) struct BeckonCowsRequest {
     pub count: u32,
 }
 
+// Cows can be released either by count (random cows leave) or by name. Exactly
+// one of the two must be given, which is checked by a struct-level validator.
+// Option<T> fields are simply absent from the JSON when they are None.
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "validate_release_request"))]
+pub(crate) struct ReleaseCowsRequest {
+    #[validate(range(min = 1))]
+    pub count: Option<u32>,
+    #[validate(length(min = 1))]
+    pub names: Option<Vec<String>>,
+}
+
+fn validate_release_request(req: &ReleaseCowsRequest) -> Result<(), ValidationError> {
+    match (&req.count, &req.names) {
+        (None, Some(names)) if names.iter().any(|name| name.trim().is_empty()) => {
+            Err(ValidationError::new("`names` must not contain empty names"))
+        },
+        (Some(_), None) | (None, Some(_)) => Ok(()),
+        _ => Err(ValidationError::new("exactly one of `count` or `names` is required")),
+    }
+}
+
+// Partial update of a cow. The ranges are the same ones make_cow() draws from,
+// so an edited cow is indistinguishable from a freshly generated one.
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "validate_update_cow_request"))]
+pub(crate) struct UpdateCowRequest {
+    #[validate(custom = "validate_cow_color")]
+    pub color: Option<String>,
+    #[validate(range(min = 5, max = 30))]
+    pub age: Option<u32>,
+    #[validate(range(min = 1300, max = 1800))]
+    pub weight: Option<u32>,
+}
+
+fn validate_update_cow_request(req: &UpdateCowRequest) -> Result<(), ValidationError> {
+    if req.color.is_none() && req.age.is_none() && req.weight.is_none() {
+        return Err(ValidationError::new("at least one of `color`, `age` or `weight` is required"));
+    }
+    Ok(())
+}
+
+// Query parameters for /cows/list. Every filter is optional, and missing filters
+// match everything. Unknown `sort`/`order` values are rejected during deserialization,
+// the rest is checked by the validators.
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = "validate_list_cows_query"))]
+pub(crate) struct ListCowsQuery {
+    #[validate(custom = "validate_cow_color")]
+    pub color: Option<String>,
+    pub min_age: Option<u32>,
+    pub max_age: Option<u32>,
+    pub min_weight: Option<u32>,
+    pub max_weight: Option<u32>,
+    #[validate(length(min = 1, max = 50))]
+    pub name_prefix: Option<String>,
+    // #[serde(default)] fills in Default::default() when the parameter is absent.
+    #[serde(default)]
+    pub sort: CowSortField,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+fn validate_cow_color(color: &str) -> Result<(), ValidationError> {
+    CowColor::try_from(color).map(|_| ()).map_err(|_| ValidationError::new("not a valid cow color"))
+}
+
+fn validate_list_cows_query(query: &ListCowsQuery) -> Result<(), ValidationError> {
+    // Destructuring a tuple of Options only matches when both bounds are present.
+    if let (Some(min), Some(max)) = (query.min_age, query.max_age) {
+        if min > max {
+            return Err(ValidationError::new("`min_age` must not exceed `max_age`"));
+        }
+    }
+    if let (Some(min), Some(max)) = (query.min_weight, query.max_weight) {
+        if min > max {
+            return Err(ValidationError::new("`min_weight` must not exceed `max_weight`"));
+        }
+    }
+    Ok(())
+}
+
+// Query parameters for the chat history endpoints, which only page.
+#[derive(Deserialize, Validate)]
+pub(crate) struct PageQuery {
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+// Enum variants can be deserialized from plain strings. rename_all maps
+// `Weight` to "weight" and so on.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CowSortField {
+    Name,
+    #[default]
+    Id,
+    Color,
+    Age,
+    Weight,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
 // The Debug trait is for pretty-printing values using the debug string formatter `{:?}`.
 // Serialize is about marshalling values into JSON to send over the wire.
-#[derive(Debug, Serialize)]
-pub(crate) struct CowListResponse {
+// Types that are part of the library's public API get `///` doc comments, which
+// end up in the generated documentation. #[non_exhaustive] keeps other crates
+// from building them with struct literals, so we can add fields later without
+// breaking them.
+
+/// The cows that were beckoned or released, as returned by `POST /cows/beckon`,
+/// `POST /cows/release` and `DELETE /cows/{name}`.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct CowListResponse {
+    /// The cows, in no particular order.
     pub cows: Vec<Cow>,
 }
 
@@ -55,42 +185,283 @@ impl Responder for CowListResponse {
     
     // Unused argument names should be prefaced with `_` for readability.
     fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
-        let body = serde_json::to_string_pretty(&self).unwrap();
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body(body)
+        pretty_json_response(&self)
     }
 }
 
-// All the fields are public, because we want to be able to destructure this type elsewhere.
+/// One page of a filtered `GET /cows/list`, plus how many cows matched overall.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct CowPageResponse {
+    /// The cows on this page, in the requested order.
+    pub cows: Vec<Cow>,
+    /// How many cows matched the filters, on all pages together.
+    pub total: u32,
+    /// How many matching cows were skipped before this page.
+    pub offset: u32,
+    /// The page size that was asked for, if any.
+    pub limit: Option<u32>,
+}
+
+impl Responder for CowPageResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+// A recorded chat. Timestamps are milliseconds since the Unix epoch and are
+// missing for chats recorded before transcripts were kept. `ended_at` is also
+// missing while the chat is still going.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatSession {
+    pub id: i64,
+    pub started_at: Option<u64>,
+    pub ended_at: Option<u64>,
+    pub duration_secs: u64,
+    pub close_reason: Option<String>,
+    pub message_count: u32,
+    // Who chatted, if the server knew.
+    pub user_id: Option<i64>,
+}
+
+// One page of a cow's chats, newest first.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatSessionPageResponse {
+    pub cow: String,
+    pub sessions: Vec<ChatSession>,
+    pub total: u32,
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+impl Responder for ChatSessionPageResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatMessage {
+    pub id: i64,
+    // "user" or "cow".
+    pub sender: String,
+    pub body: String,
+    pub sent_at: u64,
+}
+
+// One page of a chat's transcript, oldest first.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatMessagePageResponse {
+    pub session_id: i64,
+    pub messages: Vec<ChatMessage>,
+    pub total: u32,
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+impl Responder for ChatMessagePageResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+// Totals over a cow's finished chats. Chats that are still going don't count yet.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatStatsResponse {
+    pub cow: String,
+    pub sessions: u32,
+    pub total_chat_secs: u64,
+    pub average_duration_secs: f64,
+}
+
+impl Responder for ChatStatsResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OccupantsResponse {
+    pub cow: String,
+    pub occupants: Vec<Occupant>,
+}
+
+impl Responder for OccupantsResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+// Every cow that somebody is chatting with right now, by name.
+#[derive(Debug, Serialize)]
+pub(crate) struct PresenceResponse {
+    pub cows: Vec<CowPresence>,
+}
+
+impl Responder for PresenceResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CatalogReloadResponse {
+    pub source: String,
+    pub names: usize,
+    pub phrases: usize,
+    pub cows_not_in_catalog: Vec<String>,
+}
+
+impl Responder for CatalogReloadResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+// GET /healthz. If the process can answer at all, it is alive.
+#[derive(Debug, Serialize)]
+pub(crate) struct HealthResponse {
+    pub status: &'static str,
+}
+
+impl Responder for HealthResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
+    }
+}
+
+// GET /readyz. The server is ready if the database answers in time and its schema
+// is the one this build expects. The body is the same either way, only the status
+// code differs, so an orchestrator can just look at that.
+#[derive(Debug, Serialize)]
+pub(crate) struct ReadinessResponse {
+    // "ready" or "unavailable".
+    pub status: &'static str,
+    pub database: DatabaseHealth,
+    pub pool: PoolHealth,
+    // Missing if the database couldn't be asked.
+    pub schema_version: Option<u32>,
+    pub expected_schema_version: u32,
+}
+
 #[derive(Debug, Serialize)]
-pub(crate) struct Cow {
+pub(crate) struct DatabaseHealth {
+    pub ok: bool,
+    // How long getting a connection and running the query took.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+// A snapshot of the connection pool. `connections` includes the idle ones.
+#[derive(Debug, Serialize)]
+pub(crate) struct PoolHealth {
+    pub connections: u32,
+    pub idle: u32,
+    pub max_size: u32,
+}
+
+impl ReadinessResponse {
+    pub fn is_ready(&self) -> bool {
+        self.database.ok && self.schema_version == Some(self.expected_schema_version)
+    }
+}
+
+impl Responder for ReadinessResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        let mut response = pretty_json_response(&self);
+        if !self.is_ready() {
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        response
+    }
+}
+
+// Generic functions accept any type that implements the listed traits.
+fn pretty_json_response<T: Serialize>(value: &T) -> HttpResponse {
+    let body = serde_json::to_string_pretty(value).unwrap();
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body)
+}
+
+// All the fields are public, because we want to be able to destructure this type elsewhere.
+
+/// A cow in the meadow, as the API returns it.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Cow {
+    /// Unique among the cows currently in the meadow. Always starts with an uppercase letter.
     pub name: String,
+    /// Unique, and never reused while the cow is in the meadow.
     pub id: u32,
+    /// The color of the cow's coat.
     pub color: CowColor,
+    /// In years, between 5 and 30.
     pub age: u32,
+    /// In pounds, between 1300 and 1800.
     pub weight: u32,
+    /// How the cow talks in chats.
+    pub personality: Personality,
 }
 
-// We give Cow a constructor for convenience, but it can also be constructed as Cow { ...fields... }.
+// We give Cow a constructor for convenience, but inside this crate it can also be
+// constructed as Cow { ...fields... }.
 impl Cow {
-    pub fn new(name: &str, id: u32, color: CowColor, age: u32, weight: u32) -> Self {
-        Self { name: String::from(name), id, color, age, weight }
+    /// Makes a cow out of its parts. No range checks are done.
+    pub fn new(name: &str, id: u32, color: CowColor, age: u32, weight: u32, personality: Personality) -> Self {
+        Self { name: String::from(name), id, color, age, weight, personality }
+    }
+}
+
+// Same as for CowListResponse, so that a single cow can be returned from a handler.
+impl Responder for Cow {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        pretty_json_response(&self)
     }
 }
 
 impl Display for Cow {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "a cow named {} (id {}), {}, {} years old and weighs {} pounds",
-                self.name, self.id, self.color.as_ref(), self.age, self.weight)
+        write!(f, "a {} cow named {} (id {}), {}, {} years old and weighs {} pounds",
+                self.personality.as_ref(), self.name, self.id, self.color.as_ref(), self.age, self.weight)
     }
 }
 
 // The simplest knd of enum is just a finite list of literal instances.
 // Enums can also be other kinds of type unions.
-#[derive(Debug, Serialize)]
-pub(crate) enum CowColor {
-    Black, Brown, Tan, BlackWithWhitePatches, 
+
+/// The color of a cow's coat. In JSON, it is the variant name, e.g. `"BlackWithWhitePatches"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum CowColor {
+    /// Black all over.
+    Black,
+    /// Brown all over.
+    Brown,
+    /// A light, yellowish brown.
+    Tan,
+    /// Black with white patches, like a Holstein.
+    BlackWithWhitePatches,
 }
 
 // This makes it possible to get a `&str` out of a CowColor.
@@ -146,3 +517,56 @@ impl FromSql for CowColor {
         }
     }
 }
+
+/// How a cow talks. Every cow gets one at random when it is beckoned, and it
+/// decides which phrases the cow picks its chat replies from. In JSON, it is
+/// the lowercase variant name, e.g. `"grumpy"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Personality {
+    /// Looks on the bright side.
+    Cheerful,
+    /// Looks on the other side.
+    Grumpy,
+    /// Wonders what sides are, anyway.
+    Philosophical,
+}
+
+impl Personality {
+    pub(crate) const ALL: [Personality; 3] = [Personality::Cheerful, Personality::Grumpy, Personality::Philosophical];
+}
+
+impl AsRef<str> for Personality {
+    fn as_ref(&self) -> &str {
+        match self {
+            Personality::Cheerful => "cheerful",
+            Personality::Grumpy => "grumpy",
+            Personality::Philosophical => "philosophical",
+        }
+    }
+}
+
+impl TryFrom<&str> for Personality {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        // Going through ALL keeps this in step with as_ref().
+        Personality::ALL.into_iter()
+            .find(|p| p.as_ref() == s)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a valid Personality!", s))
+    }
+}
+
+impl ToSql for Personality {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_ref()))
+    }
+}
+
+impl FromSql for Personality {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s: String = FromSql::column_result(value)?;
+        Personality::try_from(&*s).map_err(|_| FromSqlError::InvalidType)
+    }
+}
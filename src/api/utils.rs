@@ -1,36 +1,72 @@
-use std::collections::HashSet;
+use std::time::{
+    SystemTime, UNIX_EPOCH,
+};
 
-use lazy_static::lazy_static;
 use rand::prelude::*;
+use serde::Deserialize;
 
-use crate::api::types::{Cow, CowColor};
-
-// This macro from the lazy_static crate allows the creation of lazily initialized
-// read-only global static variables.
-lazy_static! {
-    pub(crate) static ref COW_NAMES: HashSet<String> = [
-        "Arabella", "Bella", "Bessie", "Betty", "Bianca", "Blackjack", "Bossy",
-        "Brownie", "Buttercup", "Butterscotch", "Cayenne", "Clarabelle", "Cookie",
-        "Daisy", "Domino", "Dottie", "Flossie", "Gertie", "Ginger", "Goldie",
-        "Guenevere", "Guinness", "Henrietta", "Maggie", "Marshmallow", "Millie",
-        "Minnie", "Muffin", "Nellie", "Oreo", "Peaches", "Penelope", "Penny",
-        "Phoebe", "Popcorn", "Princess", "Rosie", "Ruby", "Smokey", "Snowflake",
-        "Speckles", "Sprinles", "Sugar", "Sweetie",
-    ].iter().map(|name| name.to_string()).collect();
-
-    // vec![...] is the Rust macro for making vectors.
-    static ref COW_PHRASES: Vec<String> = vec![
-        "Mooo! {} understands.".to_string(),
-        "Mooo! {} offers kind words of encouragement.".to_string(),
-        "Mooo! {} thinks it's all for the best.".to_string(),
-        "Mooo! {} thinks you tried your best.".to_string(),
-        "Mooo! {} can't really disagree.".to_string(),
-        "Mooo! {} appreciates you making an effort.".to_string(),
-    ];
+use crate::api::types::{
+    Cow, CowColor, Personality,
+};
+use crate::catalog::Catalog;
+
+// Words that give away how the user feels. Matching is on whole, lowercased words.
+const HAPPY_WORDS: &[&str] = &[
+    "love", "happy", "great", "good", "glad", "awesome", "wonderful", "thanks", "thank",
+    "yay", "fun", "nice", "excited", "best", "amazing", "beautiful",
+];
+const SAD_WORDS: &[&str] = &[
+    "sad", "bad", "hate", "tired", "angry", "upset", "sorry", "awful", "terrible", "lonely",
+    "worst", "cry", "crying", "miss", "lost", "failed", "hurt",
+];
+
+// What a user's message sounded like, as far as a cow can tell. Every
+// personality has a bank of phrases for every mood in the catalog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mood {
+    Happy,
+    Sad,
+    Question,
+    Neutral,
+}
+
+impl Mood {
+    pub const ALL: [Mood; 4] = [Mood::Happy, Mood::Sad, Mood::Question, Mood::Neutral];
+}
+
+impl AsRef<str> for Mood {
+    fn as_ref(&self) -> &str {
+        match self {
+            Mood::Happy => "happy",
+            Mood::Sad => "sad",
+            Mood::Question => "question",
+            Mood::Neutral => "neutral",
+        }
+    }
+}
+
+// Whichever kind of feeling word shows up more often wins. Messages without a
+// clear feeling are questions if they end with a question mark.
+pub(crate) fn read_mood(message: &str) -> Mood {
+    let lowercase = message.to_lowercase();
+    let words: Vec<&str> = lowercase.split(|c: char| !c.is_alphanumeric() && c != '\'').collect();
+    let happy = words.iter().filter(|word| HAPPY_WORDS.contains(word)).count();
+    let sad = words.iter().filter(|word| SAD_WORDS.contains(word)).count();
+    if happy > sad {
+        Mood::Happy
+    } else if sad > happy {
+        Mood::Sad
+    } else if message.trim_end().ends_with('?') {
+        Mood::Question
+    } else {
+        Mood::Neutral
+    }
 }
 
-pub(crate) fn make_cow(name: &str, id: u32) -> Cow {
-    let mut random = thread_rng();
+// Generic over the random number generator, so that a seeded one makes the
+// outcome repeatable. `?Sized` also admits `dyn RngCore` trait objects.
+pub(crate) fn make_cow<R: Rng + ?Sized>(name: &str, id: u32, random: &mut R) -> Cow {
     // x..y is exclusive, x..=y is inclusive.
     // Number literals often need to be annotated for type.
     let color = match random.gen_range(0_u32..=3) {
@@ -43,13 +79,24 @@ pub(crate) fn make_cow(name: &str, id: u32) -> Cow {
     };
     let age = random.gen_range(5_u32..=30);
     let weight = random.gen_range(1300_u32..=1800);
-    Cow::new(name, id, color, age, weight)
+    let personality = *Personality::ALL.choose(random).unwrap();
+    Cow::new(name, id, color, age, weight, personality)
 }
 
-pub(crate) fn make_cow_phrase(name: &str) -> String {
-    let mut random = thread_rng();
-    let template = COW_PHRASES.choose(&mut random).unwrap();
+// The cow's reply to what the user said, from the phrases in the catalog.
+pub(crate) fn make_cow_phrase<R: Rng + ?Sized>(catalog: &Catalog,
+                                               name: &str,
+                                               personality: Personality,
+                                               said: &str,
+                                               random: &mut R) -> String {
+    let phrases = catalog.phrases(personality, read_mood(said));
+    let template = phrases.choose(random).unwrap();
     // I would normally use format!() here, but it only accepts string literals,
     // so it can evaluate them at compile time.
     template.replace("{}", name)
 }
+
+// Milliseconds since the Unix epoch, the timestamp format of chat frames and transcripts.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
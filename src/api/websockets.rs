@@ -1,5 +1,5 @@
-use std::{
-    time::{Duration, Instant}, sync::Arc,
+use std::time::{
+    Duration, Instant,
 };
 
 // Some libraries have a prelude, so that they can dump all the relevant types,
@@ -8,55 +8,212 @@ use std::{
 // scope for it to add the new methods.
 use actix::prelude::*;
 use actix_web_actors::ws::{
-    Message, ProtocolError, WebsocketContext,
+    CloseCode, CloseReason, Message, ProtocolError, WebsocketContext,
 };
+use rand::rngs::StdRng;
 
-use r2d2_sqlite::rusqlite::named_params;
-
-use crate::api::utils::make_cow_phrase;
-use crate::db::{
-    types::MyPool, queries::INSERT_CHAT_SESSION,
+use crate::api::protocol::{
+    ChatMode, FrameError, FrameFactory, Inbound, parse_inbound,
+};
+use crate::api::rooms::{
+    Broadcast, Heartbeat, Join, Leave, RoomEvent, RoomRegistry,
+};
+use crate::api::types::{
+    Cow, Personality,
 };
+use crate::api::utils::{
+    make_cow_phrase, unix_millis,
+};
+use crate::auth::User;
+use crate::catalog::CatalogStore;
+use crate::config::{ChatConfig, RateLimitConfig};
+use crate::db::transcripts::{
+    Sender, TranscriptEvent, TranscriptWriter, next_session_key,
+};
+use crate::logging::{request_id, with_request_id};
+use crate::metrics::Metrics;
+use crate::ratelimit::{ChatThrottle, LIMITED_CHAT_MESSAGE, Verdict};
+use crate::shutdown::Draining;
 
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+// Everything a chat needs from the rest of the server, bundled up so that it
+// can be shared with the chat handler as a single piece of app data.
+#[derive(Clone)]
+pub(crate) struct ChatServices {
+    pub transcripts: Addr<TranscriptWriter>,
+    pub rooms: Addr<RoomRegistry>,
+    pub catalog: CatalogStore,
+    pub config: ChatConfig,
+    pub metrics: Metrics,
+    pub draining: Draining,
+    pub rate_limit: RateLimitConfig,
+}
 
 pub struct CowChat {
     started: Instant,
     heartbeat: Instant,
-    // We give this type a reference to the connection pool instead of just a
-    // single connection, because otherwise it would hold the connection for the
-    // potentially unbounded length of an entire chat session.
-    // An `Arc` is an asynchonous reference-counted pointer to a value, making
-    // the value shareable between threads.
-    db_pool: Arc<MyPool>,
+    // The chat doesn't touch the database at all. Everything worth keeping is
+    // sent to the transcript writer, which does the writing on its own thread.
+    // An `Addr` is a cheap, cloneable handle for sending messages to an actor.
+    transcripts: Addr<TranscriptWriter>,
+    rooms: Addr<RoomRegistry>,
+    catalog: CatalogStore,
+    session_key: u64,
+    // What the other people in the room see this chat as: the user's name, or
+    // a guest name if the server doesn't require API keys.
+    name: String,
+    user_id: Option<i64>,
+    // Why the chat ended, once we know. Chats that just drop off are "disconnected".
+    close_reason: Option<&'static str>,
     cow: String,
+    personality: Personality,
+    client_timeout: Duration,
+    heartbeat_interval: Duration,
+    mode: ChatMode,
+    frames: FrameFactory,
+    // Picks the cow's replies. Seeded per chat, see crate::random.
+    random: StdRng,
+    metrics: Metrics,
+    // Said by either side, for the messages-per-session metric.
+    messages: u64,
+    // The ID of the request that opened the chat, for the chat's log lines.
+    request_id: Option<String>,
+    // How fast the client may talk. None without rate limits.
+    throttle: Option<ChatThrottle>,
 }
 
 impl CowChat {
-    pub fn new(db_pool: Arc<MyPool>, cow: &str) -> Self {
+    pub fn new(services: &ChatServices, cow: &Cow, user: Option<User>, mode: ChatMode, random: StdRng) -> Self {
         let now = Instant::now();
+        let session_key = next_session_key();
         // Instant is Copy, so we can pass it by value to multiple consumers with impunity.
         // Foo { bar: bar } can be abbreviated to Foo { bar }.
-        Self { started: now, heartbeat: now, db_pool, cow: String::from(cow) }
+        Self {
+            started: now,
+            heartbeat: now,
+            transcripts: services.transcripts.clone(),
+            rooms: services.rooms.clone(),
+            catalog: services.catalog.clone(),
+            session_key,
+            name: user.as_ref().map(|user| user.name.clone()).unwrap_or_else(|| format!("guest-{}", session_key)),
+            user_id: user.map(|user| user.id),
+            close_reason: None,
+            cow: cow.name.clone(),
+            personality: cow.personality,
+            client_timeout: services.config.client_timeout(),
+            heartbeat_interval: services.config.heartbeat_interval(),
+            mode,
+            frames: FrameFactory::default(),
+            random,
+            metrics: services.metrics.clone(),
+            messages: 0,
+            // new() runs in the chat handler, while the request is still going.
+            request_id: request_id(),
+            throttle: ChatThrottle::new(&services.rate_limit, now),
+        }
     }
 
-    // For sotring the timestamp of the most recent ping or pong.
+    // For sotring the timestamp of the most recent ping or pong. The registry
+    // keeps its own copy for the presence endpoints.
     fn refresh_heartbeat(&mut self) {
         self.heartbeat = Instant::now();
+        self.rooms.do_send(Heartbeat { room: self.cow.clone(), key: self.session_key });
+    }
+
+    // do_send() queues a message without waiting for an answer. It only fails
+    // if the writer's mailbox is gone, and then there's nothing left to do.
+    fn record_message(&mut self, sender: Sender, body: &str) {
+        self.messages += 1;
+        self.transcripts.do_send(TranscriptEvent::Message {
+            key: self.session_key,
+            sender,
+            body: body.to_string(),
+            sent_at: unix_millis(),
+        });
+    }
+
+    fn broadcast(&self, event: RoomEvent) {
+        self.rooms.do_send(Broadcast { room: self.cow.clone(), from_key: self.session_key, event });
+    }
+
+    // Everything the user says goes through here. The cow answers, and the rest of
+    // the room hears both. The exchange is only recorded in this chat's transcript.
+    fn user_said(&mut self, text: &str, context: &mut <CowChat as Actor>::Context) {
+        self.record_message(Sender::User, text);
+        self.broadcast(RoomEvent::Said { name: self.name.clone(), text: text.to_string() });
+        let catalog = self.catalog.current();
+        let phrase = make_cow_phrase(&catalog, &self.cow, self.personality, text, &mut self.random);
+        self.record_message(Sender::Cow, &phrase);
+        match self.mode {
+            ChatMode::PlainText => context.text(phrase.clone()),
+            ChatMode::Json => context.text(self.frames.say(&self.cow, &phrase)),
+        }
+        self.broadcast(RoomEvent::CowSaid { text: phrase });
+    }
+
+    // Whether the client's latest text frame may go ahead. If not, the client is
+    // told to slow down, or the chat is closed for good if it didn't listen.
+    // Throttled frames are dropped without a reply or a transcript entry.
+    fn within_rate_limit(&mut self, context: &mut <CowChat as Actor>::Context) -> bool {
+        let Some(throttle) = self.throttle.as_mut() else {
+            return true;
+        };
+        let per_minute = throttle.per_minute();
+        match throttle.check(Instant::now()) {
+            Verdict::Allowed => true,
+            Verdict::Throttled(wait) => {
+                log::debug!("Throttled a chat message to {}", self.cow);
+                self.metrics.record_rate_limited(LIMITED_CHAT_MESSAGE);
+                let notice = format!("Slow down, at most {} messages a minute. The next one is accepted in {:.1}s.",
+                                     per_minute, wait.as_secs_f64());
+                match self.mode {
+                    ChatMode::PlainText => context.text(format!("* {}", notice)),
+                    ChatMode::Json => context.text(self.frames.error(&FrameError::new("rate_limited", notice, None))),
+                }
+                false
+            },
+            Verdict::Exceeded => {
+                log::info!("Closing a chat with {} that kept sending too many messages", self.cow);
+                self.metrics.record_rate_limited(LIMITED_CHAT_MESSAGE);
+                // 1008 "policy violation".
+                self.close_reason = Some("rate_limited");
+                context.close(Some(CloseReason::from((CloseCode::Policy, "Too many messages"))));
+                context.stop();
+                false
+            },
+        }
     }
 
     // Write some info about the chat to the DB when a chat ends.
     fn record_session_in_db(&self) {
-        let conn = self.db_pool.get().unwrap();
-        let mut stmt = conn.prepare_cached(INSERT_CHAT_SESSION).unwrap();
         // Duration overrides minus, so Duration - Duration = Duration.
         let duration = (self.heartbeat - self.started).as_secs();
-        log::debug!("Recording chat session with {} that lasted for {} seconds...", self.cow, duration);
-        // An if-let statement can also do destructuring.
-        let params = named_params! {":cow_name": &self.cow, ":duration": duration};
-        if let Err(e) = stmt.execute(params) {
-            log::error!("Failed to record chat session in DB: {}", e);
+        let close_reason = self.close_reason.unwrap_or("disconnected");
+        log::debug!("Recording chat session with {} that lasted for {} seconds ({})...",
+                    self.cow, duration, close_reason);
+        self.metrics.record_chat_session(duration, self.messages, close_reason);
+        self.transcripts.do_send(TranscriptEvent::SessionEnded {
+            key: self.session_key,
+            ended_at: unix_millis(),
+            duration_secs: duration,
+            close_reason: close_reason.to_string(),
+        });
+    }
+
+    // Text frames in the structured protocol. Every say gets an ack before the
+    // cow's reply, and anything we can't make sense of gets an error frame back
+    // instead of being treated as chat.
+    fn handle_json_frame(&mut self, text: &str, context: &mut <CowChat as Actor>::Context) {
+        match parse_inbound(text) {
+            Ok(Inbound::Say { id, text }) => {
+                log::debug!("{} was told: {}", self.cow, text);
+                context.text(self.frames.ack(&id));
+                self.user_said(&text, context);
+            },
+            Ok(Inbound::Typing) => {},
+            Err(error) => {
+                log::debug!("Rejected chat frame ({}): {}", error.code, error.message);
+                context.text(self.frames.error(&error));
+            },
         }
     }
 
@@ -64,13 +221,16 @@ impl CowChat {
     // a type to a trait that it implements, to access trait-specific fields or
     // methods (in this case, the associated Context type).
     fn start_beating(&self, context: &mut <CowChat as Actor>::Context) {
-        context.run_interval(HEARTBEAT_INTERVAL, |actor, context| {
-            if Instant::now().duration_since(actor.heartbeat) > CLIENT_TIMEOUT {
-                log::warn!("Websocket client missed heartbeat, disconnecting!");
+        context.run_interval(self.heartbeat_interval, |actor, context| {
+            if Instant::now().duration_since(actor.heartbeat) > actor.client_timeout {
+                with_request_id(actor.request_id.clone(), || {
+                    log::warn!("Chat client missed its heartbeat, disconnecting it from {}", actor.cow);
+                });
+                actor.close_reason = Some("heartbeat_timeout");
                 context.stop();
             } else {
                 // We ping single zero byte as a keep-alive every INTERVAL seconds.
-                context.ping(&[b'0']);
+                context.ping(b"0");
             }
         });
     }
@@ -80,17 +240,44 @@ impl Actor for CowChat {
     type Context = WebsocketContext<Self>;
 
     fn started(&mut self, context: &mut Self::Context) {
+        self.metrics.open_chat_sessions.inc();
+        self.transcripts.do_send(TranscriptEvent::SessionStarted {
+            key: self.session_key,
+            cow: self.cow.clone(),
+            started_at: unix_millis(),
+            user_id: self.user_id,
+        });
+        // An actor can hand out its own address, to be called back on.
+        self.rooms.do_send(Join {
+            room: self.cow.clone(),
+            key: self.session_key,
+            name: self.name.clone(),
+            recipient: context.address().recipient(),
+        });
         self.start_beating(context);
+        if self.mode == ChatMode::Json {
+            let greeting = format!("You are chatting with {} as {}.", self.cow, self.name);
+            context.text(self.frames.system(&greeting));
+        }
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
-        self.record_session_in_db();
+        self.metrics.open_chat_sessions.dec();
+        self.rooms.do_send(Leave { room: self.cow.clone(), key: self.session_key });
+        with_request_id(self.request_id.clone(), || self.record_session_in_db());
     }
 }
 
 impl StreamHandler<Result<Message, ProtocolError>> for CowChat {
     fn handle(&mut self, item: Result<Message, ProtocolError>, context: &mut Self::Context) {
-        log::debug!("WS msg from client: {:?}", item);
+        with_request_id(self.request_id.clone(), || self.handle_client_message(item, context));
+    }
+}
+
+impl CowChat {
+    // Everything the client sends, from StreamHandler::handle().
+    fn handle_client_message(&mut self, item: Result<Message, ProtocolError>, context: &mut <CowChat as Actor>::Context) {
+        log::debug!("Chat message from client: {:?}", item);
         match item {
             Ok(Message::Ping(msg)) => {
                 self.refresh_heartbeat();
@@ -100,16 +287,53 @@ impl StreamHandler<Result<Message, ProtocolError>> for CowChat {
                 self.refresh_heartbeat();
             },
             Ok(Message::Binary(_)) => {
-                log::warn!("Received unsupported binary message!");
+                log::warn!("Chat client sent an unsupported binary message");
+                if self.mode == ChatMode::Json {
+                    let error = FrameError::new("unsupported_binary", "Frames must be JSON text", None);
+                    context.text(self.frames.error(&error));
+                }
             },
-            Ok(Message::Text(_)) => {
-                context.text(make_cow_phrase(&self.cow));
+            Ok(Message::Text(_)) if !self.within_rate_limit(context) => {},
+            Ok(Message::Text(text)) => match self.mode {
+                ChatMode::PlainText => self.user_said(&text, context),
+                ChatMode::Json => self.handle_json_frame(&text, context),
             },
             Ok(Message::Close(reason)) => {
+                self.close_reason = Some("client_closed");
                 context.close(reason);
                 context.stop();
             },
-            _ => context.stop(),
+            _ => {
+                self.close_reason = Some("protocol_error");
+                context.stop();
+            },
         }
     }
 }
+
+// What the rest of the room is up to. Plain-text clients get it as lines of text,
+// JSON clients as say frames (with the speaker in `from`) and system frames.
+impl Handler<RoomEvent> for CowChat {
+    type Result = ();
+
+    fn handle(&mut self, event: RoomEvent, context: &mut Self::Context) {
+        let frame = match (self.mode, event) {
+            (_, RoomEvent::ServerShutdown) => {
+                // 1001 "going away", the close code for a server on its way down.
+                self.close_reason = Some("server_shutdown");
+                context.close(Some(CloseReason::from((CloseCode::Away, "Server shutting down"))));
+                context.stop();
+                return;
+            },
+            (ChatMode::PlainText, RoomEvent::Joined { name }) => format!("* {} joined", name),
+            (ChatMode::PlainText, RoomEvent::Left { name }) => format!("* {} left", name),
+            (ChatMode::PlainText, RoomEvent::Said { name, text }) => format!("{}: {}", name, text),
+            (ChatMode::PlainText, RoomEvent::CowSaid { text }) => text,
+            (ChatMode::Json, RoomEvent::Joined { name }) => self.frames.system(&format!("{} joined", name)),
+            (ChatMode::Json, RoomEvent::Left { name }) => self.frames.system(&format!("{} left", name)),
+            (ChatMode::Json, RoomEvent::Said { name, text }) => self.frames.say(&name, &text),
+            (ChatMode::Json, RoomEvent::CowSaid { text }) => self.frames.say(&self.cow, &text),
+        };
+        context.text(frame);
+    }
+}
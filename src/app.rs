@@ -0,0 +1,245 @@
+use std::{
+    sync::Arc,
+    time::Instant,
+};
+
+use actix::{Actor, Addr, Arbiter};
+use actix_web::{
+    App, Error,
+    body::MessageBody,
+    dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    middleware::NormalizePath,
+    web::{Data, PathConfig, ServiceConfig, delete, get, patch, post, scope},
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::api::handlers::{
+    chat_stats_handler, count_cows_handler, beckon_cows_handler, get_cow_by_id_handler,
+    get_cow_handler, healthz_handler, list_chat_messages_handler, metrics_handler, list_chat_sessions_handler, list_cows_handler,
+    list_occupants_handler, presence_handler, presence_stream_handler, readyz_handler, release_cow_handler,
+    reload_catalog_handler, release_cows_handler, update_cow_handler, websocket_cowchat_handler,
+};
+use crate::api::rooms::RoomRegistry;
+use crate::api::websockets::ChatServices;
+use crate::auth::{self, Access};
+use crate::catalog::CatalogStore;
+use crate::config::{AuthConfig, Config, HealthConfig};
+use crate::db::types::MyPool;
+use crate::db::transcripts::TranscriptWriter;
+use crate::errors::{path_error_handler, validation_error_handler};
+use crate::logging::request_ids;
+use crate::metrics::Metrics;
+use crate::shutdown::Draining;
+use crate::middleware::{Hooks, MiddlewareHook};
+use crate::random::RngSource;
+use crate::ratelimit::{RateLimiter, limit_failed_auth, limit_requests};
+
+// Everything the handlers share, created once and handed to every copy of the
+// app. `Data` is the Actix thread-safe box for sharing stuff between threads.
+// Clones of `Data` are just clones of the pointer, not the thing inside, so
+// cloning the whole state for each server thread is cheap.
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pool: Data<MyPool>,
+    chat_services: Data<ChatServices>,
+    rooms: Data<Addr<RoomRegistry>>,
+    catalog: Data<CatalogStore>,
+    rng: Data<RngSource>,
+    health: Data<HealthConfig>,
+    metrics: Data<Metrics>,
+    auth: Data<AuthConfig>,
+    // Only the middleware uses it, so it isn't app data.
+    limiter: RateLimiter,
+}
+
+impl AppState {
+    // The auth middleware for a scope, see crate::auth.
+    fn require<S>(&self, access: Access)
+        -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+        S::Future: 'static,
+    {
+        auth::require(self.auth.get_ref().clone(), self.pool.get_ref().clone(), access)
+    }
+
+    // The rate limit middleware for a scope, see crate::ratelimit. It shares its
+    // buckets with the other scopes.
+    fn limit_requests<S>(&self)
+        -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+        S::Future: 'static,
+    {
+        limit_requests(self.limiter.clone(), self.metrics.get_ref().clone())
+    }
+
+    // Its counterpart outside the auth middleware, for requests without a valid key.
+    fn limit_failed_auth<S>(&self)
+        -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+        S::Future: 'static,
+    {
+        limit_failed_auth(self.limiter.clone(), self.metrics.get_ref().clone())
+    }
+
+    // For shutting the chats down, see crate::shutdown.
+    pub fn chat_services(&self) -> &ChatServices {
+        &self.chat_services
+    }
+
+    // Starts the actors behind the chats, so this has to run inside an Actix
+    // system: #[actix_web::main] in the server, #[actix_web::test] in tests.
+    // The pool is expected to be migrated already, and to report its waits to
+    // `metrics` if those should be measured (see Metrics::pool_events()).
+    pub fn new(pool: MyPool, catalog: CatalogStore, config: &Config, metrics: Metrics) -> Self {
+        // The transcript writer gets an Arbiter (an event loop on its own thread) to
+        // itself, so its database writes never hold up the server threads.
+        let writer_pool = pool.clone();
+        let transcripts = TranscriptWriter::start_in_arbiter(&Arbiter::new().handle(), move |_| {
+            TranscriptWriter::new(writer_pool)
+        });
+        // One registry for all chat rooms, shared by every server thread. It runs on
+        // the current thread's Arbiter, since all it does is pass messages around.
+        let rooms = RoomRegistry::default().start();
+        let chat_services = ChatServices {
+            transcripts,
+            rooms: rooms.clone(),
+            catalog: catalog.clone(),
+            config: config.chat.clone(),
+            metrics: metrics.clone(),
+            draining: Draining::default(),
+            rate_limit: config.rate_limit.clone(),
+        };
+        Self {
+            pool: Data::new(pool),
+            chat_services: Data::new(chat_services),
+            rooms: Data::new(rooms),
+            catalog: Data::new(catalog),
+            rng: Data::new(RngSource::new(&config.random)),
+            health: Data::new(config.health.clone()),
+            metrics: Data::new(metrics),
+            auth: Data::new(config.auth.clone()),
+            limiter: RateLimiter::new(&config.rate_limit),
+        }
+    }
+}
+
+// Extra routes from an embedding application, see CowchatServer::routes().
+pub(crate) type RouteHook = Arc<dyn Fn(&mut ServiceConfig) + Send + Sync>;
+
+// What an embedding application added to the server. Like the state, it is
+// cloned into every server thread.
+#[derive(Clone, Default)]
+pub(crate) struct Extras {
+    pub routes: Vec<RouteHook>,
+    pub middleware: Vec<MiddlewareHook>,
+}
+
+// The whole app, as each server thread runs it. The return type only promises
+// "some service factory", because the real one is a tower of middleware types.
+// Middleware wraps from the inside out: the hooks see every request after the
+// path was normalized, and the request log sees what the hooks did. Everything
+// inside request_ids logs with the request's ID.
+pub(crate) fn build_app(state: &AppState, extras: &Extras) -> App<impl ServiceFactory<
+    ServiceRequest,
+    Config = (),
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+    InitError = (),
+>> {
+    let state = state.clone();
+    let routes = extras.routes.clone();
+    App::new().configure(move |cfg| {
+                  configure_app(cfg, &state);
+                  for route in &routes {
+                      route(cfg);
+                  }
+              })
+              .wrap(Hooks::new(extras.middleware.clone()))
+              .wrap_fn(request_ids) // request IDs, and a log line for every request
+              .wrap(NormalizePath::trim()) // middleware to trim trailing slashes from paths
+}
+
+// Registers the shared state and every built-in route on an app.
+pub(crate) fn configure_app(cfg: &mut ServiceConfig, state: &AppState) {
+    // A "scope" in this case s just a group of routes.
+    let cows_scope = scope("/cows").route("/count", get().to(count_cows_handler))
+                                   .route("/beckon", post().to(beckon_cows_handler))
+                                   .route("/list", get().to(list_cows_handler))
+                                   .route("/release", post().to(release_cows_handler))
+                                   .route("/presence", get().to(presence_handler))
+                                   .route("/presence/stream", get().to(presence_stream_handler))
+                                   .route("/chat/{cow_name}", get().to(websocket_cowchat_handler))
+                                   .route("/id/{cow_id}", get().to(get_cow_by_id_handler))
+                                   // Catch-all name paths go last, so they don't shadow the fixed ones.
+                                   .route("/{cow_name}", get().to(get_cow_handler))
+                                   .route("/{cow_name}", patch().to(update_cow_handler))
+                                   .route("/{cow_name}", delete().to(release_cow_handler))
+                                   .route("/{cow_name}/sessions", get().to(list_chat_sessions_handler))
+                                   .route("/{cow_name}/stats", get().to(chat_stats_handler))
+                                   .route("/{cow_name}/occupants", get().to(list_occupants_handler))
+                                   // The last wrap runs first, so refused requests are counted too,
+                                   // and the rate limits know who the user is, or that there is none.
+                                   .wrap_fn(state.limit_requests())
+                                   .wrap_fn(state.require(Access::User))
+                                   .wrap_fn(state.limit_failed_auth())
+                                   .wrap_fn(request_metrics(state.metrics.get_ref().clone()));
+    let admin_scope = scope("/admin").route("/catalog/reload", post().to(reload_catalog_handler))
+                                     .wrap_fn(state.limit_requests())
+                                     .wrap_fn(state.require(Access::Admin))
+                                     .wrap_fn(state.limit_failed_auth());
+    let sessions_scope = scope("/sessions").route("/{session_id}/messages", get().to(list_chat_messages_handler))
+                                           .wrap_fn(state.limit_requests())
+                                           .wrap_fn(state.require(Access::User))
+                                           .wrap_fn(state.limit_failed_auth());
+
+    // Invalid requests get the same problem+json responses as other errors.
+    let json_config = actix_web_validator::JsonConfig::default().error_handler(validation_error_handler);
+    let query_config = actix_web_validator::QueryConfig::default().error_handler(validation_error_handler);
+    let path_config = PathConfig::default().error_handler(path_error_handler);
+
+    cfg.app_data(state.pool.clone()) // shared stuff
+       .app_data(state.chat_services.clone())
+       .app_data(state.rooms.clone())
+       .app_data(state.catalog.clone())
+       .app_data(state.rng.clone())
+       .app_data(state.health.clone())
+       .app_data(state.metrics.clone())
+       .app_data(state.auth.clone())
+       .app_data(json_config)
+       .app_data(query_config)
+       .app_data(path_config)
+       // Probes for orchestrators, outside of every scope.
+       .route("/healthz", get().to(healthz_handler))
+       .route("/readyz", get().to(readyz_handler))
+       .route("/metrics", get().to(metrics_handler))
+       .service(cows_scope) // routing
+       .service(sessions_scope)
+       .service(admin_scope);
+}
+
+// Middleware for the /cows scope, written as a plain function with wrap_fn()
+// instead of a Transform/Service pair. It counts and times every request by its
+// route pattern. For a chat, that is only the time until the upgrade.
+fn request_metrics<S>(metrics: Metrics)
+    -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    move |req, service| {
+        let metrics = metrics.clone();
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let response = service.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            // None for paths that no route matched.
+            let route = response.request().match_pattern().unwrap_or_else(|| "(unmatched)".to_string());
+            metrics.record_request(&method, &route, response.status().as_u16(), started.elapsed());
+            Ok(response)
+        })
+    }
+}
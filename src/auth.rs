@@ -0,0 +1,238 @@
+use std::{
+    fmt::{Display, Formatter},
+    future::{Ready, ready},
+};
+
+use actix_web::{
+    Error, FromRequest, HttpMessage, HttpRequest,
+    dev::{Payload, Service, ServiceRequest, ServiceResponse},
+    http::header::{AUTHORIZATION, UPGRADE},
+    web::{Data, Query},
+};
+use futures_util::future::LocalBoxFuture;
+use r2d2_sqlite::rusqlite::{self, OptionalExtension, named_params};
+use rand::{RngCore, rngs::OsRng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::api::utils::unix_millis;
+use crate::config::AuthConfig;
+use crate::db::{
+    queries::{
+        CHECK_FOR_USER_QUERY, FIND_USER_BY_KEY_QUERY, INSERT_API_KEY_QUERY, INSERT_USER_QUERY, LIST_USERS_QUERY,
+        REVOKE_API_KEYS_QUERY,
+    },
+    types::MyPool,
+};
+use crate::errors::CowError;
+
+// With auth.enabled, every request to /cows, /sessions and /admin needs an API
+// key, as `Authorization: Bearer <key>`. Browsers can't set headers on a
+// WebSocket, so chat upgrades may bring the key as `?access_token=<key>`
+// instead (RFC 6750 calls it that). Anywhere else, keys in the URL are refused,
+// since URLs end up in logs and browser histories.
+//
+// Each key belongs to a user, and a request with a valid key carries its User
+// in the request extensions for the handlers. Admins can do everything; other
+// users can't release cows or touch /admin. Users and keys are managed with
+// `cowchat users`.
+
+pub(crate) const ACCESS_TOKEN_PARAM: &str = "access_token";
+// Makes keys recognizable, e.g. to secret scanners.
+const KEY_PREFIX: &str = "cowchat_";
+
+#[derive(Clone, Debug)]
+pub(crate) struct User {
+    pub id: i64,
+    pub name: String,
+    pub admin: bool,
+}
+
+// What a scope (or handler) requires of the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Access {
+    User,
+    Admin,
+}
+
+// Only hashes are stored. Keys are long and random, so a plain SHA-256 is
+// enough; a slow password hash would only slow down every request.
+pub(crate) fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// 32 bytes from the operating system's generator, never from the seedable
+// generator in crate::random.
+pub(crate) fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}{}", KEY_PREFIX, hex)
+}
+
+fn find_user_by_key(conn: &rusqlite::Connection, key: &str) -> anyhow::Result<Option<User>> {
+    let user = conn.prepare_cached(FIND_USER_BY_KEY_QUERY)?
+        .query_row(named_params! {":key_hash": hash_key(key)}, |row| {
+            Ok(User { id: row.get(0)?, name: row.get(1)?, admin: row.get(2)? })
+        })
+        .optional()?;
+    Ok(user)
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+fn is_websocket_upgrade(req: &ServiceRequest) -> bool {
+    req.headers().get(UPGRADE).and_then(|value| value.to_str().ok())
+       .map(|value| value.eq_ignore_ascii_case("websocket"))
+       .unwrap_or(false)
+}
+
+// The key the request came with, if any.
+fn presented_key(req: &ServiceRequest) -> Result<Option<String>, CowError> {
+    if let Some(header) = req.headers().get(AUTHORIZATION) {
+        // Auth schemes are case-insensitive (RFC 9110, section 11.1), so "bearer" counts too.
+        let key = header.to_str().ok()
+            .and_then(|value| value.split_once(' '))
+            .and_then(|(scheme, key)| scheme.eq_ignore_ascii_case("bearer").then_some(key));
+        return match key {
+            Some(key) => Ok(Some(key.trim().to_string())),
+            None => Err(CowError::Unauthorized("The Authorization header must be \"Bearer <API key>\"".to_string())),
+        };
+    }
+    // An unparseable query string is the handler's problem, not ours.
+    let token = Query::<TokenQuery>::from_query(req.query_string()).ok().and_then(|query| query.0.access_token);
+    match token {
+        Some(_) if !is_websocket_upgrade(req) => Err(CowError::Unauthorized(format!(
+            "{} is only accepted for chats, other requests need an Authorization header", ACCESS_TOKEN_PARAM))),
+        token => Ok(token),
+    }
+}
+
+// Whether `user` may go where `access` is required.
+fn authorize(user: Option<&User>, access: Access) -> Result<(), CowError> {
+    match (user, access) {
+        (None, _) => Err(CowError::Unauthorized("An API key is required".to_string())),
+        (Some(user), Access::Admin) if !user.admin => Err(CowError::Forbidden(format!("{} is not an admin", user.name))),
+        _ => Ok(()),
+    }
+}
+
+fn authenticate(req: &ServiceRequest, pool: &MyPool, access: Access) -> Result<User, CowError> {
+    let Some(key) = presented_key(req)? else {
+        return Err(CowError::Unauthorized("An API key is required".to_string()));
+    };
+    let conn = pool.get()?;
+    let Some(user) = find_user_by_key(&conn, &key)? else {
+        return Err(CowError::Unauthorized("Unknown or revoked API key".to_string()));
+    };
+    authorize(Some(&user), access)?;
+    Ok(user)
+}
+
+// Middleware for a scope that requires `access`, in the style of request_metrics()
+// in app.rs. Turned off, it lets everything through without a User.
+pub(crate) fn require<S>(config: AuthConfig, pool: MyPool, access: Access)
+    -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    move |req, service| {
+        if !config.enabled {
+            return Box::pin(service.call(req));
+        }
+        match authenticate(&req, &pool, access) {
+            Ok(user) => {
+                req.extensions_mut().insert(user);
+                Box::pin(service.call(req))
+            },
+            Err(e) => {
+                log::info!("Refused {} {}: {}", req.method(), req.path(), e);
+                let response = req.error_response(e);
+                Box::pin(async move { Ok(response) })
+            },
+        }
+    }
+}
+
+// Handlers that only admins may use take one of these, for the routes that
+// share a scope with routes for everyone. It fails the request with a 403 for
+// other users, and does nothing when auth is turned off.
+pub(crate) struct AdminOnly;
+
+impl FromRequest for AdminOnly {
+    type Error = CowError;
+    type Future = Ready<Result<Self, CowError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let enabled = req.app_data::<Data<AuthConfig>>().map(|config| config.enabled).unwrap_or(false);
+        if !enabled {
+            return ready(Ok(AdminOnly));
+        }
+        ready(authorize(req.extensions().get::<User>(), Access::Admin).map(|_| AdminOnly))
+    }
+}
+
+// What `cowchat users list` shows about each user.
+pub(crate) struct UserSummary {
+    pub id: i64,
+    pub name: String,
+    pub admin: bool,
+    pub active_keys: u32,
+}
+
+impl Display for UserSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let role = if self.admin { "admin" } else { "user" };
+        write!(f, "{:>4} {:<24} {:<5} {} active key(s)", self.id, self.name, role, self.active_keys)
+    }
+}
+
+fn user_exists(conn: &rusqlite::Connection, name: &str) -> anyhow::Result<bool> {
+    Ok(conn.prepare_cached(CHECK_FOR_USER_QUERY)?.query_row(named_params! {":user_name": name}, |row| row.get(0))?)
+}
+
+// Creates a user together with a first key, and returns the key. This is the
+// only time the key is known; the database only gets its hash.
+pub(crate) fn create_user(conn: &mut rusqlite::Connection, name: &str, admin: bool) -> anyhow::Result<String> {
+    if user_exists(conn, name)? {
+        return Err(CowError::Conflict(format!("There is already a user called {}", name)).into());
+    }
+    let tx = conn.transaction()?;
+    tx.prepare_cached(INSERT_USER_QUERY)?
+      .execute(named_params! {":user_name": name, ":is_admin": admin, ":created_at": unix_millis()})?;
+    let key = create_key(&tx, name)?;
+    tx.commit()?;
+    Ok(key)
+}
+
+// Another key for an existing user. Their other keys keep working.
+pub(crate) fn create_key(conn: &rusqlite::Connection, name: &str) -> anyhow::Result<String> {
+    if !user_exists(conn, name)? {
+        return Err(CowError::NotFound(format!("No user called {}", name)).into());
+    }
+    let key = generate_key();
+    conn.prepare_cached(INSERT_API_KEY_QUERY)?
+        .execute(named_params! {":user_name": name, ":key_hash": hash_key(&key), ":created_at": unix_millis()})?;
+    Ok(key)
+}
+
+// Revokes all of a user's keys and returns how many there were.
+pub(crate) fn revoke_keys(conn: &rusqlite::Connection, name: &str) -> anyhow::Result<usize> {
+    if !user_exists(conn, name)? {
+        return Err(CowError::NotFound(format!("No user called {}", name)).into());
+    }
+    Ok(conn.prepare_cached(REVOKE_API_KEYS_QUERY)?
+        .execute(named_params! {":user_name": name, ":revoked_at": unix_millis()})?)
+}
+
+pub(crate) fn list_users(conn: &rusqlite::Connection) -> anyhow::Result<Vec<UserSummary>> {
+    let mut stmt = conn.prepare_cached(LIST_USERS_QUERY)?;
+    let users = stmt.query_map([], |row| {
+        Ok(UserSummary { id: row.get(0)?, name: row.get(1)?, admin: row.get(2)?, active_keys: row.get(3)? })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(users)
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+
+use crate::api::types::Personality;
+use crate::api::utils::Mood;
+use crate::config::CatalogConfig;
+use crate::errors::CowError;
+
+// The catalog that ships with the server, compiled into the binary. It is used
+// whenever no catalog file is configured.
+const BUILT_IN_CATALOG: &str = include_str!("../catalog.toml");
+
+// The names cows can have and the phrases they reply with. See catalog.toml for
+// the file format. Names keep the order of the file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Catalog {
+    pub names: Vec<String>,
+    pub phrases: HashMap<Personality, HashMap<Mood, Vec<String>>>,
+}
+
+impl Catalog {
+    fn parse(contents: &str, source: &str) -> anyhow::Result<Self> {
+        // The toml error is folded into the message, since the admin endpoint only
+        // shows the outermost one.
+        let catalog: Catalog = toml::from_str(contents).map_err(|e| anyhow!("Invalid catalog {}: {}", source, e))?;
+        catalog.validate(source)?;
+        Ok(catalog)
+    }
+
+    // Collects every problem, like Config::validate() does.
+    fn validate(&self, source: &str) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if self.names.is_empty() {
+            problems.push("there must be at least one name".to_string());
+        }
+        let mut seen = HashSet::new();
+        for name in &self.names {
+            // Names end up in URLs, where they are capitalized before being looked up.
+            let starts_uppercase = name.chars().next().map(char::is_uppercase).unwrap_or(false);
+            if !starts_uppercase || name.chars().count() > 50 || name.contains(['/', '?', '#']) {
+                problems.push(format!("{:?} must start with an uppercase letter, be at most 50 characters \
+                                       and contain no '/', '?' or '#'", name));
+            }
+            // insert() returns false if the value was already there.
+            if !seen.insert(name) {
+                problems.push(format!("{:?} is listed more than once", name));
+            }
+        }
+        for personality in Personality::ALL {
+            for mood in Mood::ALL {
+                let phrases = self.phrases.get(&personality).and_then(|moods| moods.get(&mood));
+                match phrases {
+                    Some(phrases) if !phrases.is_empty() => {
+                        for phrase in phrases.iter().filter(|phrase| phrase.matches("{}").count() != 1) {
+                            problems.push(format!("{:?} must contain exactly one {{}}", phrase));
+                        }
+                    },
+                    _ => problems.push(format!("phrases.{}.{} needs at least one phrase",
+                                               personality.as_ref(), mood.as_ref())),
+                }
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Invalid catalog {}:\n  {}", source, problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    // The validation above guarantees that there is a bank for every combination.
+    pub fn phrases(&self, personality: Personality, mood: Mood) -> &[String] {
+        &self.phrases[&personality][&mood]
+    }
+}
+
+// The catalog in use, shared by the request handlers, the chats and whatever
+// reloads it. Readers clone the inner Arc and let go of the lock right away, so
+// a chat holds on to the catalog it started a reply with while a reload swaps
+// in the next one.
+#[derive(Clone)]
+pub(crate) struct CatalogStore {
+    current: Arc<RwLock<Arc<Catalog>>>,
+    path: Option<String>,
+}
+
+impl CatalogStore {
+    pub fn load(config: &CatalogConfig) -> anyhow::Result<Self> {
+        let catalog = load_catalog(config.path.as_deref())?;
+        Ok(Self { current: Arc::new(RwLock::new(Arc::new(catalog))), path: config.path.clone() })
+    }
+
+    pub fn current(&self) -> Arc<Catalog> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Re-reads the catalog file. If it is broken, the old catalog stays in place.
+    pub fn reload(&self) -> anyhow::Result<Arc<Catalog>> {
+        if self.path.is_none() {
+            return Err(CowError::Conflict("No catalog file is configured, the built-in catalog can't be reloaded".to_string()).into());
+        }
+        let catalog = Arc::new(load_catalog(self.path.as_deref())?);
+        *self.current.write().unwrap() = catalog.clone();
+        Ok(catalog)
+    }
+
+    pub fn source(&self) -> &str {
+        self.path.as_deref().unwrap_or("(built-in)")
+    }
+}
+
+fn load_catalog(path: Option<&str>) -> anyhow::Result<Catalog> {
+    match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read catalog {}", path))?;
+            Catalog::parse(&contents, path)
+        },
+        None => Catalog::parse(BUILT_IN_CATALOG, "(built-in)"),
+    }
+}
+
+// Reloads the catalog whenever the process gets SIGHUP. Every listener gets the
+// signal, so this runs alongside the certificate reload when TLS is enabled.
+pub(crate) async fn reload_on_sighup(store: CatalogStore) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    if store.path.is_none() {
+        return;
+    }
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Could not listen for SIGHUP, catalog reload is disabled: {}", e);
+            return;
+        },
+    };
+    while hangups.recv().await.is_some() {
+        match store.reload() {
+            Ok(catalog) => log::info!("Reloaded catalog {} with {} names", store.source(), catalog.names.len()),
+            Err(e) => log::error!("Keeping the old catalog, reload failed: {:#}", e),
+        }
+    }
+}
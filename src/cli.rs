@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use r2d2_sqlite::rusqlite::Connection;
+
+use crate::auth::{create_key, create_user, list_users, revoke_keys};
+use crate::config::LogFormat;
+use crate::db::migrations::{current_schema_version, migrate_up, migration_status};
+
+// The command line is described declaratively. clap derives the parser, the
+// --help text (from these doc comments) and the error messages from the struct.
+// Every setting flag is an Option, because a flag that isn't given must not
+// override whatever the config file or environment said.
+//
+// The doc comments double as the --help text, which is why they read like it.
+//
+// These types are public because Config::load() takes a Cli, and every new
+// setting adds a flag, so they are all #[non_exhaustive]. Other crates get a
+// Cli from Cli::parse() and can't build one (or match on one) field by field.
+
+/// chat with cows near you
+#[derive(Parser)]
+#[command(name = "cowchat", version)]
+#[non_exhaustive]
+pub struct Cli {
+    /// TOML config file [default: ./cowchat.toml, if present]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Address to listen on
+    #[arg(long)]
+    pub host: Option<String>,
+    /// Port to listen on
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Number of worker threads
+    #[arg(long)]
+    pub workers: Option<u32>,
+    /// Seconds to wait for chats to be recorded when shutting down
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_grace_secs: Option<u64>,
+    /// SQLite database file
+    #[arg(long, value_name = "PATH")]
+    pub db_path: Option<String>,
+    /// Log filter, e.g. "info" or "cowchat=debug,actix_web=info"
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Log line format: "text" or "json"
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Seconds without a heartbeat before a chat client is disconnected
+    #[arg(long, value_name = "SECS")]
+    pub client_timeout_secs: Option<u64>,
+    /// Seconds between pings sent to chat clients
+    #[arg(long, value_name = "SECS")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Serve HTTPS instead of HTTP
+    #[arg(long)]
+    pub tls: bool,
+    /// PEM certificate chain for HTTPS
+    #[arg(long, value_name = "PATH")]
+    pub tls_cert: Option<String>,
+    /// PEM private key for HTTPS
+    #[arg(long, value_name = "PATH")]
+    pub tls_key: Option<String>,
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS
+    #[arg(long, value_name = "PORT")]
+    pub tls_redirect_http_port: Option<u16>,
+    /// Catalog of cow names and phrases [default: built in]
+    #[arg(long, value_name = "PATH")]
+    pub catalog: Option<String>,
+    /// Seed for cow generation and chat replies, to make them repeatable
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Let requests set their own seed with the X-Cowchat-Seed header (for tests only)
+    #[arg(long)]
+    pub test_mode: bool,
+    /// Milliseconds /readyz waits for the database before reporting it unavailable
+    #[arg(long, value_name = "MS")]
+    pub readiness_timeout_ms: Option<u64>,
+    /// Require an API key for /cows, /sessions and /admin
+    #[arg(long)]
+    pub auth: bool,
+    /// Limit how fast clients may send requests and chat messages
+    #[arg(long)]
+    pub rate_limit: bool,
+    /// Requests per minute each client may make, with --rate-limit
+    #[arg(long, value_name = "N")]
+    pub requests_per_minute: Option<u32>,
+    /// Messages per minute each chat may send, with --rate-limit
+    #[arg(long, value_name = "N")]
+    pub chat_messages_per_minute: Option<u32>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+#[non_exhaustive]
+pub enum Command {
+    /// Inspect or apply database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Manage users and their API keys
+    Users {
+        #[command(subcommand)]
+        action: UsersAction,
+    },
+}
+
+#[derive(Subcommand)]
+#[non_exhaustive]
+pub enum MigrateAction {
+    /// List migrations and whether they have been applied
+    Status,
+    /// Apply pending migrations without starting the server
+    Up,
+}
+
+#[derive(Subcommand)]
+#[non_exhaustive]
+pub enum UsersAction {
+    /// List users and how many active keys they have
+    List,
+    /// Create a user and print their first API key
+    Add {
+        name: String,
+        /// Let the user release cows and use /admin
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Print a new API key for a user, in addition to their others
+    NewKey { name: String },
+    /// Revoke all of a user's API keys
+    RevokeKeys { name: String },
+}
+
+// `cowchat migrate status` lists every migration and whether it has been applied.
+// `cowchat migrate up` applies the pending ones, which the server also does on startup.
+
+/// Runs a `cowchat migrate` subcommand against a database file and returns the
+/// process exit code.
+pub fn run_migrate_command(action: &MigrateAction, db_path: &str) -> i32 {
+    let conn = Connection::open(db_path).map_err(anyhow::Error::from);
+    let outcome = conn.and_then(|mut conn| match action {
+        MigrateAction::Status => {
+            println!("Schema version: {}", current_schema_version(&conn)?);
+            for status in migration_status(&conn)? {
+                println!("{}", status);
+            }
+            Ok(())
+        },
+        MigrateAction::Up => {
+            let applied = migrate_up(&mut conn)?;
+            println!("Applied {} migration(s), schema version is now {}.",
+                     applied.len(), current_schema_version(&conn)?);
+            Ok(())
+        },
+    });
+    match outcome {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+// `cowchat users ...` works on the same database as the server, and brings its
+// schema up to date first, so that users can be set up before the first start.
+// Keys are printed exactly once; only their hashes are stored.
+
+/// Runs a `cowchat users` subcommand against a database file and returns the
+/// process exit code.
+pub fn run_users_command(action: &UsersAction, db_path: &str) -> i32 {
+    let conn = Connection::open(db_path).map_err(anyhow::Error::from);
+    let outcome = conn.and_then(|mut conn| {
+        migrate_up(&mut conn)?;
+        match action {
+            UsersAction::List => {
+                for user in list_users(&conn)? {
+                    println!("{}", user);
+                }
+            },
+            UsersAction::Add { name, admin } => {
+                let key = create_user(&mut conn, name, *admin)?;
+                println!("Created {}. Their API key is:\n{}", name, key);
+            },
+            UsersAction::NewKey { name } => {
+                let key = create_key(&conn, name)?;
+                println!("New API key for {}:\n{}", name, key);
+            },
+            UsersAction::RevokeKeys { name } => {
+                println!("Revoked {} key(s) of {}.", revoke_keys(&conn, name)?, name);
+            },
+        }
+        Ok(())
+    });
+    match outcome {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{
+    Deserialize, Serialize,
+};
+
+use crate::cli::Cli;
+
+const DEFAULT_CONFIG_FILE: &str = "cowchat.toml";
+const ENV_PREFIX: &str = "COWCHAT_";
+
+// Server settings come from up to four layers, each overriding the one before:
+// built-in defaults, a TOML file, COWCHAT_* environment variables, and
+// command-line flags. The result is validated once, at startup.
+//
+// #[serde(default)] fills in any field missing from the file from Default::default(),
+// so a config file only needs to mention what it changes. deny_unknown_fields
+// turns typos in the file into errors instead of silently ignored settings.
+// Every section is #[non_exhaustive], since new settings keep coming. Other crates
+// start from Config::default() and change the fields they care about.
+
+/// Everything the server can be configured with. See `cowchat.example.toml` for
+/// the file format and the defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// Where and how to listen.
+    pub server: ServerConfig,
+    /// The SQLite database.
+    pub database: DatabaseConfig,
+    /// WebSocket chat timing.
+    pub chat: ChatConfig,
+    /// Logging. Only used by the `cowchat` binary, embedding applications set up their own logger.
+    pub log: LogConfig,
+    /// HTTPS.
+    pub tls: TlsConfig,
+    /// Cow names and chat phrases.
+    pub catalog: CatalogConfig,
+    /// Random generation of cows and replies.
+    pub random: RandomConfig,
+    /// The health and readiness endpoints.
+    pub health: HealthConfig,
+    /// API keys.
+    pub auth: AuthConfig,
+    /// Limits on how fast clients may send requests and chat messages.
+    pub rate_limit: RateLimitConfig,
+}
+
+/// Where and how to listen.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// Address to listen on. Defaults to `localhost`.
+    pub host: String,
+    /// Port to listen on. Defaults to 3000.
+    pub port: u16,
+    /// Number of worker threads. Defaults to 5. The database pool keeps a
+    /// connection for each, and has room for at least 10.
+    pub workers: u32,
+    /// Seconds to wait on SIGTERM or SIGINT for open chats to be closed and
+    /// recorded before stopping anyway. Defaults to 10. Requests still running
+    /// after that get at most another 2 seconds, not a grace period of their own.
+    pub shutdown_grace_secs: u64,
+}
+
+/// The SQLite database. Ignored if the server is given a pool of its own.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct DatabaseConfig {
+    /// The database file. Defaults to `cowchat.db`.
+    pub path: String,
+}
+
+/// WebSocket chat timing.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ChatConfig {
+    /// Seconds without a heartbeat before a chat client is disconnected. Defaults to 10.
+    pub client_timeout_secs: u64,
+    /// Seconds between pings sent to chat clients. Defaults to 5.
+    pub heartbeat_interval_secs: u64,
+}
+
+// With TLS enabled, the server only speaks HTTPS (and wss:// for chats) on
+// server.port. Optionally, plain HTTP on redirect_http_port redirects there.
+
+/// HTTPS (and `wss://` for chats) on `server.port`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct TlsConfig {
+    /// Serve HTTPS instead of HTTP. Defaults to false.
+    pub enabled: bool,
+    /// PEM certificate chain. Defaults to `cert.pem`.
+    pub cert_path: String,
+    /// PEM private key. Defaults to `key.pem`.
+    pub key_path: String,
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS.
+    pub redirect_http_port: Option<u16>,
+}
+
+// Without a path, the catalog built into the binary is used.
+
+/// Cow names and chat phrases.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct CatalogConfig {
+    /// A catalog file in the format of `catalog.toml`. Defaults to the built-in catalog.
+    pub path: Option<String>,
+}
+
+// Without a seed, every run is different. test_mode lets requests bring their
+// own seed in an X-Cowchat-Seed header, which is for tests, not for production.
+
+/// Random generation of cows and replies.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct RandomConfig {
+    /// Makes cows and replies repeatable. Defaults to a different seed every run.
+    pub seed: Option<u64>,
+    /// Lets requests pick their own seed with an `X-Cowchat-Seed` header. For tests only.
+    pub test_mode: bool,
+}
+
+// Users and their keys live in the database, see `cowchat users --help`.
+
+/// API keys.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct AuthConfig {
+    /// Requires an API key for `/cows`, `/sessions` and `/admin`. Defaults to false.
+    pub enabled: bool,
+}
+
+// Each client gets a bucket of tokens that refills at a steady rate, and every
+// request takes one. A burst can use up the whole bucket at once, after which
+// the client has to wait for it to refill. Chats get a bucket of their own.
+
+/// Limits on how fast clients may send requests and chat messages.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct RateLimitConfig {
+    /// Limits requests to `/cows`, `/sessions` and `/admin`, and messages in chats. Defaults to false.
+    pub enabled: bool,
+    /// Requests a client may make per minute, on average. Defaults to 120.
+    pub requests_per_minute: u32,
+    /// Requests a client may make at once. Defaults to 30.
+    pub request_burst: u32,
+    /// Messages a chat may send per minute, on average. Defaults to 30.
+    pub chat_messages_per_minute: u32,
+    /// Messages a chat may send at once. Defaults to 10.
+    pub chat_message_burst: u32,
+    /// Throttle notices a chat gets in a row before it is closed. Defaults to 3.
+    pub chat_throttle_warnings: u32,
+}
+
+// /readyz fails if the database doesn't answer within the timeout, so that an
+// orchestrator stops sending traffic to a server whose database is stuck.
+
+/// The health and readiness endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct HealthConfig {
+    /// Milliseconds `/readyz` waits for a database connection and a query. Defaults to 1000.
+    pub readiness_timeout_ms: u64,
+}
+
+/// Logging.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct LogConfig {
+    /// Log filter, with the same syntax as `RUST_LOG`. Levels include
+    /// trace/debug/info/warn/error/off. Defaults to `debug`, or to `RUST_LOG`
+    /// if that is set.
+    pub level: String,
+    /// How log lines look. Defaults to [`LogFormat::Text`].
+    pub format: LogFormat,
+}
+
+/// The format of log lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum LogFormat {
+    /// One line of text per entry, for people.
+    #[default]
+    Text,
+    /// One JSON object per entry, for log pipelines.
+    Json,
+}
+
+// For COWCHAT_LOG_FORMAT and --log-format, which are parsed with str::parse().
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown log format {:?}, expected \"text\" or \"json\"", s),
+        }
+    }
+}
+
+// These defaults are the values the server used before it was configurable.
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { host: "localhost".to_string(), port: 3000, workers: 5, shutdown_grace_secs: 10 }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self { path: "cowchat.db".to_string() }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self { client_timeout_secs: 10, heartbeat_interval_secs: 5 }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self { level: "debug".to_string(), format: LogFormat::default() }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self { enabled: false, cert_path: "cert.pem".to_string(), key_path: "key.pem".to_string(), redirect_http_port: None }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_minute: 120,
+            request_burst: 30,
+            chat_messages_per_minute: 30,
+            chat_message_burst: 10,
+            chat_throttle_warnings: 3,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { readiness_timeout_ms: 1000 }
+    }
+}
+
+impl ServerConfig {
+    /// `shutdown_grace_secs` as a Duration.
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+impl ChatConfig {
+    /// `client_timeout_secs` as a Duration.
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    /// `heartbeat_interval_secs` as a Duration.
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+}
+
+impl HealthConfig {
+    /// `readiness_timeout_ms` as a Duration.
+    pub fn readiness_timeout(&self) -> Duration {
+        Duration::from_millis(self.readiness_timeout_ms)
+    }
+}
+
+impl Config {
+    /// Builds the effective configuration from all layers, the way the `cowchat`
+    /// binary does. An explicitly requested config file (`--config` or
+    /// `COWCHAT_CONFIG`) must exist, the default `cowchat.toml` is optional.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let explicit_file = match &cli.config {
+            Some(path) => Some(path.clone()),
+            None => env_var::<PathBuf>("CONFIG")?,
+        };
+        let mut config = match explicit_file {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a TOML config file. Settings it leaves out keep their defaults.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        // context() adds a message in front of an error on its way up.
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        // `if let Some(x) = ...?` reads as: if the variable is set (and parses), use it.
+        if let Some(host) = env_var("HOST")? { self.server.host = host; }
+        if let Some(port) = env_var("PORT")? { self.server.port = port; }
+        if let Some(workers) = env_var("WORKERS")? { self.server.workers = workers; }
+        if let Some(secs) = env_var("SHUTDOWN_GRACE_SECS")? { self.server.shutdown_grace_secs = secs; }
+        if let Some(path) = env_var("DB_PATH")? { self.database.path = path; }
+        // RUST_LOG is what Rust programs are usually told their log filter with,
+        // so it counts, but the more specific COWCHAT_LOG_LEVEL wins.
+        if let Ok(level) = std::env::var("RUST_LOG") { self.log.level = level; }
+        if let Some(level) = env_var("LOG_LEVEL")? { self.log.level = level; }
+        if let Some(format) = env_var("LOG_FORMAT")? { self.log.format = format; }
+        if let Some(secs) = env_var("CLIENT_TIMEOUT_SECS")? { self.chat.client_timeout_secs = secs; }
+        if let Some(secs) = env_var("HEARTBEAT_INTERVAL_SECS")? { self.chat.heartbeat_interval_secs = secs; }
+        if let Some(enabled) = env_var("TLS_ENABLED")? { self.tls.enabled = enabled; }
+        if let Some(path) = env_var("TLS_CERT_PATH")? { self.tls.cert_path = path; }
+        if let Some(path) = env_var("TLS_KEY_PATH")? { self.tls.key_path = path; }
+        if let Some(port) = env_var("TLS_REDIRECT_HTTP_PORT")? { self.tls.redirect_http_port = Some(port); }
+        if let Some(path) = env_var("CATALOG_PATH")? { self.catalog.path = Some(path); }
+        if let Some(seed) = env_var("RANDOM_SEED")? { self.random.seed = Some(seed); }
+        if let Some(test_mode) = env_var("RANDOM_TEST_MODE")? { self.random.test_mode = test_mode; }
+        if let Some(ms) = env_var("READINESS_TIMEOUT_MS")? { self.health.readiness_timeout_ms = ms; }
+        if let Some(enabled) = env_var("AUTH_ENABLED")? { self.auth.enabled = enabled; }
+        if let Some(enabled) = env_var("RATE_LIMIT_ENABLED")? { self.rate_limit.enabled = enabled; }
+        if let Some(rate) = env_var("REQUESTS_PER_MINUTE")? { self.rate_limit.requests_per_minute = rate; }
+        if let Some(burst) = env_var("REQUEST_BURST")? { self.rate_limit.request_burst = burst; }
+        if let Some(rate) = env_var("CHAT_MESSAGES_PER_MINUTE")? { self.rate_limit.chat_messages_per_minute = rate; }
+        if let Some(burst) = env_var("CHAT_MESSAGE_BURST")? { self.rate_limit.chat_message_burst = burst; }
+        if let Some(warnings) = env_var("CHAT_THROTTLE_WARNINGS")? { self.rate_limit.chat_throttle_warnings = warnings; }
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(host) = &cli.host { self.server.host = host.clone(); }
+        if let Some(port) = cli.port { self.server.port = port; }
+        if let Some(workers) = cli.workers { self.server.workers = workers; }
+        if let Some(secs) = cli.shutdown_grace_secs { self.server.shutdown_grace_secs = secs; }
+        if let Some(path) = &cli.db_path { self.database.path = path.clone(); }
+        if let Some(level) = &cli.log_level { self.log.level = level.clone(); }
+        if let Some(format) = cli.log_format { self.log.format = format; }
+        if let Some(secs) = cli.client_timeout_secs { self.chat.client_timeout_secs = secs; }
+        if let Some(secs) = cli.heartbeat_interval_secs { self.chat.heartbeat_interval_secs = secs; }
+        if cli.tls { self.tls.enabled = true; }
+        if let Some(path) = &cli.tls_cert { self.tls.cert_path = path.clone(); }
+        if let Some(path) = &cli.tls_key { self.tls.key_path = path.clone(); }
+        if let Some(port) = cli.tls_redirect_http_port { self.tls.redirect_http_port = Some(port); }
+        if let Some(path) = &cli.catalog { self.catalog.path = Some(path.clone()); }
+        if let Some(seed) = cli.seed { self.random.seed = Some(seed); }
+        if cli.test_mode { self.random.test_mode = true; }
+        if let Some(ms) = cli.readiness_timeout_ms { self.health.readiness_timeout_ms = ms; }
+        if cli.auth { self.auth.enabled = true; }
+        if cli.rate_limit { self.rate_limit.enabled = true; }
+        if let Some(rate) = cli.requests_per_minute { self.rate_limit.requests_per_minute = rate; }
+        if let Some(rate) = cli.chat_messages_per_minute { self.rate_limit.chat_messages_per_minute = rate; }
+    }
+
+    /// Checks the settings against each other. The error lists every problem
+    /// instead of stopping at the first one, so a broken deployment can be fixed
+    /// in one go.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if self.server.host.trim().is_empty() {
+            problems.push("server.host must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if self.server.workers == 0 {
+            problems.push("server.workers must be at least 1".to_string());
+        }
+        if self.database.path.trim().is_empty() {
+            problems.push("database.path must not be empty".to_string());
+        }
+        if self.chat.heartbeat_interval_secs == 0 {
+            problems.push("chat.heartbeat_interval_secs must be at least 1".to_string());
+        }
+        if self.chat.heartbeat_interval_secs >= self.chat.client_timeout_secs {
+            problems.push(format!("chat.heartbeat_interval_secs ({}) must be less than chat.client_timeout_secs ({})",
+                                  self.chat.heartbeat_interval_secs, self.chat.client_timeout_secs));
+        }
+        if self.tls.enabled {
+            if self.tls.cert_path.trim().is_empty() || self.tls.key_path.trim().is_empty() {
+                problems.push("tls.cert_path and tls.key_path are required when TLS is enabled".to_string());
+            }
+            if self.tls.redirect_http_port == Some(self.server.port) {
+                problems.push("tls.redirect_http_port must differ from server.port".to_string());
+            }
+        } else if self.tls.redirect_http_port.is_some() {
+            problems.push("tls.redirect_http_port requires tls.enabled".to_string());
+        }
+        if self.catalog.path.as_deref().map(|path| path.trim().is_empty()).unwrap_or(false) {
+            problems.push("catalog.path must not be empty (leave it out to use the built-in catalog)".to_string());
+        }
+        if self.health.readiness_timeout_ms == 0 {
+            problems.push("health.readiness_timeout_ms must be at least 1".to_string());
+        }
+        let rates = [
+            ("rate_limit.requests_per_minute", self.rate_limit.requests_per_minute),
+            ("rate_limit.request_burst", self.rate_limit.request_burst),
+            ("rate_limit.chat_messages_per_minute", self.rate_limit.chat_messages_per_minute),
+            ("rate_limit.chat_message_burst", self.rate_limit.chat_message_burst),
+        ];
+        for (name, value) in rates {
+            if self.rate_limit.enabled && value == 0 {
+                problems.push(format!("{} must be at least 1 (turn rate_limit.enabled off for no limits)", name));
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// The configuration in the format of the config file.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+// Reads COWCHAT_<NAME> and parses it into whatever type the caller asks for.
+// FromStr is the trait behind str::parse().
+fn env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>> {
+    let key = format!("{}{}", ENV_PREFIX, name);
+    match std::env::var(&key) {
+        Ok(value) => value.parse().map(Some).map_err(|_| anyhow!("Invalid value for {}: {:?}", key, value)),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::{
+    Display, Formatter,
+};
+
+use anyhow::{anyhow, bail};
+use r2d2_sqlite::{
+    rusqlite, rusqlite::{named_params, OptionalExtension},
+};
+use sha2::{Digest, Sha256};
+
+// A migration is a numbered chunk of SQL. Migrations are applied in order, each
+// exactly once, and every applied migration is recorded in `schema_version`
+// together with a checksum of its SQL. Applied migrations must never be edited;
+// schema changes go into a new file with the next number.
+pub(crate) struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// include_str!() embeds a file's contents into the binary at compile time,
+// so the server doesn't need the migrations directory at runtime.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../../migrations/0001_initial.sql") },
+    Migration { version: 2, name: "chat_transcripts", sql: include_str!("../../migrations/0002_chat_transcripts.sql") },
+    Migration { version: 3, name: "cow_personalities", sql: include_str!("../../migrations/0003_cow_personalities.sql") },
+    Migration { version: 4, name: "api_keys", sql: include_str!("../../migrations/0004_api_keys.sql") },
+];
+
+const CREATE_SCHEMA_VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    checksum VARCHAR(64) NOT NULL,
+    applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);";
+const APPLIED_MIGRATIONS_QUERY: &str = "SELECT version, name, checksum, applied_at FROM schema_version ORDER BY version;";
+const INSERT_SCHEMA_VERSION_QUERY: &str = "INSERT INTO
+    schema_version (version, name, checksum)
+    VALUES (:version, :name, :checksum);";
+const CURRENT_SCHEMA_VERSION_QUERY: &str = "SELECT MAX(version) FROM schema_version;";
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        // Hex-encoding a digest is just formatting it with `{:x}`.
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+// What a database knows about one migration.
+struct AppliedMigration {
+    version: u32,
+    name: String,
+    checksum: String,
+    applied_at: String,
+}
+
+// Enum variants can carry data, like little structs.
+pub(crate) enum MigrationState {
+    Applied { applied_at: String },
+    Pending,
+    // The SQL in the binary no longer matches what was applied.
+    Modified { applied_at: String },
+    // Applied by a newer build that this binary doesn't know about.
+    Unknown { applied_at: String },
+}
+
+pub(crate) struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub state: MigrationState,
+}
+
+impl Display for MigrationStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // {:04} pads the number with zeroes to four digits, like the file names.
+        let state = match &self.state {
+            MigrationState::Applied { applied_at } => format!("applied at {}", applied_at),
+            MigrationState::Pending => "pending".to_string(),
+            MigrationState::Modified { applied_at } => format!("MODIFIED since it was applied at {}", applied_at),
+            MigrationState::Unknown { applied_at, .. } => format!("UNKNOWN to this build, applied at {}", applied_at),
+        };
+        write!(f, "{:04} {:<24} {}", self.version, self.name, state)
+    }
+}
+
+fn applied_migrations(conn: &rusqlite::Connection) -> anyhow::Result<Vec<AppliedMigration>> {
+    conn.execute_batch(CREATE_SCHEMA_VERSION_TABLE)?;
+    let mut stmt = conn.prepare_cached(APPLIED_MIGRATIONS_QUERY)?;
+    let applied = stmt.query_map([], |row| {
+        Ok(AppliedMigration {
+            version: row.get(0)?,
+            name: row.get(1)?,
+            checksum: row.get(2)?,
+            applied_at: row.get(3)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(applied)
+}
+
+// Compares the migrations in this binary against the ones recorded in the database.
+pub(crate) fn migration_status(conn: &rusqlite::Connection) -> anyhow::Result<Vec<MigrationStatus>> {
+    let applied = applied_migrations(conn)?;
+    let mut statuses: Vec<MigrationStatus> = MIGRATIONS.iter().map(|m| {
+        let state = match applied.iter().find(|a| a.version == m.version) {
+            None => MigrationState::Pending,
+            Some(a) if a.checksum != m.checksum() => MigrationState::Modified { applied_at: a.applied_at.clone() },
+            Some(a) => MigrationState::Applied { applied_at: a.applied_at.clone() },
+        };
+        MigrationStatus { version: m.version, name: m.name.to_string(), state }
+    }).collect();
+    for a in applied.iter().filter(|a| MIGRATIONS.iter().all(|m| m.version != a.version)) {
+        statuses.push(MigrationStatus {
+            version: a.version,
+            name: a.name.clone(),
+            state: MigrationState::Unknown { applied_at: a.applied_at.clone() },
+        });
+    }
+    statuses.sort_by_key(|s| s.version);
+    Ok(statuses)
+}
+
+// Applies all pending migrations and returns the versions that were applied.
+// Refuses to touch a database whose history doesn't match this binary.
+pub(crate) fn migrate_up(conn: &mut rusqlite::Connection) -> anyhow::Result<Vec<u32>> {
+    for status in migration_status(conn)? {
+        match status.state {
+            MigrationState::Modified { .. } => {
+                bail!("Migration {:04} ({}) was changed after it was applied!", status.version, status.name)
+            },
+            MigrationState::Unknown { .. } => {
+                bail!("Database has migration {:04} ({}), which this build doesn't know about!", status.version, status.name)
+            },
+            _ => {},
+        }
+    }
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS {
+        // Each migration and its schema_version row commit together, or not at all.
+        let tx = conn.transaction()?;
+        let already_applied: Option<u32> = tx
+            .query_row("SELECT version FROM schema_version WHERE version = :version;",
+                       named_params! {":version": migration.version}, |row| row.get(0))
+            .optional()?;
+        if already_applied.is_some() {
+            continue;
+        }
+        log::info!("Applying migration {:04} ({})...", migration.version, migration.name);
+        tx.execute_batch(migration.sql)
+          .map_err(|e| anyhow!("Migration {:04} ({}) failed: {}", migration.version, migration.name, e))?;
+        tx.execute(INSERT_SCHEMA_VERSION_QUERY, named_params! {
+            ":version": migration.version,
+            ":name": migration.name,
+            ":checksum": migration.checksum(),
+        })?;
+        tx.commit()?;
+        newly_applied.push(migration.version);
+    }
+    Ok(newly_applied)
+}
+
+// The version a database has once every migration in this binary is applied.
+pub(crate) fn latest_schema_version() -> u32 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+// The highest applied migration, or 0 for a database that has never been migrated.
+pub(crate) fn current_schema_version(conn: &rusqlite::Connection) -> anyhow::Result<u32> {
+    conn.execute_batch(CREATE_SCHEMA_VERSION_TABLE)?;
+    let version: Option<u32> = conn.query_row(CURRENT_SCHEMA_VERSION_QUERY, [], |row| row.get(0))?;
+    Ok(version.unwrap_or(0))
+}
@@ -2,46 +2,96 @@
 // Both modules and their members are subject to visibility rules.
 // For a consumer to refer to the member of a module, both the module and the
 // relevant member must be visible.
-pub(crate) mod utils {
 
-    use r2d2_sqlite::rusqlite;
-
-    pub(crate) fn init_db_schema(conn: &rusqlite::Connection) {
-        // Multiline strings are supported.
-        conn.execute_batch("
-            BEGIN;
-            CREATE TABLE IF NOT EXISTS cows (
-                cow_name VARCHAR(50) PRIMARY KEY,
-                cow_id INTEGER UNIQUE,
-                cow_color VARCHAR(20) NOT NULL,
-                cow_age INTEGER NOT NULL,
-                cow_weight INTEGER NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS chat_sessions (
-                chat_session_id INTEGER PRIMARY KEY,
-                cow_id INTEGER,
-                duration INTEGER NOT NULL,
-                FOREIGN KEY(cow_id) REFERENCES cows (cow_id)
-            );
-            COMMIT;
-        ").unwrap(); // TODO: better error handling?
-    }
-}
+// Modules can also live in their own file, like migrations.rs next to this one.
+pub(crate) mod migrations;
+pub(crate) mod transcripts;
 
 pub(crate) mod queries {
+    // The schema these queries run against is defined by the SQL files in /migrations.
     // Constants need explicit type annotation.
-    pub(crate) const LIST_COWS_QUERY: &str = "SELECT * FROM cows;";
+    // The list queries are completed at runtime with WHERE/ORDER BY/LIMIT clauses
+    // built from the request's filters. Values are always bound as parameters.
+    pub(crate) const LIST_COWS_QUERY: &str = "SELECT * FROM cows";
+    pub(crate) const COUNT_FILTERED_COWS_QUERY: &str = "SELECT COUNT(*) FROM cows";
+    pub(crate) const GET_COW_BY_NAME_QUERY: &str = "SELECT * FROM cows WHERE cow_name = :cow_name;";
+    pub(crate) const GET_COW_BY_ID_QUERY: &str = "SELECT * FROM cows WHERE cow_id = :cow_id;";
     pub(crate) const CHECK_FOR_COW_QUERY: &str = "SELECT 0 <> (SELECT COUNT(*) FROM cows WHERE cow_name = :cow_name);";
+    // The cheapest query that proves the database answers.
+    pub(crate) const HEALTH_CHECK_QUERY: &str = "SELECT 1;";
     pub(crate) const COUNT_COWS_QUERY: &str = "SELECT COUNT(*) FROM cows;";
     pub(crate) const DISTINCT_COW_NAMES_QUERY: &str = "SELECT DISTINCT cow_name FROM cows;";
     pub(crate) const MAX_COW_ID_QUERY: &str = "SELECT COALESCE(MAX(cow_id), 0) FROM cows;";
     pub(crate) const INSERT_COW_QUERY: &str = "INSERT INTO
-        cows (cow_name, cow_id, cow_color, cow_age, cow_weight)
-        VALUES (:cow_name, :cow_id, :cow_color, :cow_age, :cow_weight);";
+        cows (cow_name, cow_id, cow_color, cow_age, cow_weight, cow_personality)
+        VALUES (:cow_name, :cow_id, :cow_color, :cow_age, :cow_weight, :cow_personality);";
+    // A session row is created when a chat starts, so that its messages have
+    // something to point to, and completed when the chat ends.
     pub(crate) const INSERT_CHAT_SESSION: &str = "INSERT INTO
-        chat_sessions (cow_id, duration)
-        SELECT cow_id, :duration FROM
-            (SELECT cow_id FROM cows WHERE cow_name LIKE :cow_name COLLATE NOCASE);";
+        chat_sessions (cow_id, duration, started_at, user_id)
+        VALUES ((SELECT cow_id FROM cows WHERE cow_name LIKE :cow_name COLLATE NOCASE), 0, :started_at, :user_id);";
+    pub(crate) const FINISH_CHAT_SESSION: &str = "UPDATE chat_sessions
+        SET duration = :duration, ended_at = :ended_at, close_reason = :close_reason
+        WHERE chat_session_id = :chat_session_id;";
+    // The session may have been deleted along with its cow in the meantime.
+    pub(crate) const INSERT_CHAT_MESSAGE: &str = "INSERT INTO
+        chat_messages (chat_session_id, sender, body, sent_at)
+        SELECT :chat_session_id, :sender, :body, :sent_at
+        WHERE EXISTS (SELECT 1 FROM chat_sessions WHERE chat_session_id = :chat_session_id);";
+    // COALESCE keeps the current value for any attribute that isn't being changed.
+    pub(crate) const UPDATE_COW_QUERY: &str = "UPDATE cows SET
+        cow_color = COALESCE(:cow_color, cow_color),
+        cow_age = COALESCE(:cow_age, cow_age),
+        cow_weight = COALESCE(:cow_weight, cow_weight)
+        WHERE cow_name = :cow_name;";
+    // A released cow takes its chat history with it. Its cow_id may be handed
+    // out again by the next beckon, so keeping the rows would misattribute them.
+    pub(crate) const DELETE_CHAT_MESSAGES_FOR_COW_QUERY: &str = "DELETE FROM chat_messages
+        WHERE chat_session_id IN (SELECT chat_session_id FROM chat_sessions
+            WHERE cow_id IN (SELECT cow_id FROM cows WHERE cow_name = :cow_name));";
+    pub(crate) const DELETE_CHAT_SESSIONS_FOR_COW_QUERY: &str = "DELETE FROM chat_sessions
+        WHERE cow_id IN (SELECT cow_id FROM cows WHERE cow_name = :cow_name);";
+    // Chats that are still going have a started_at but no ended_at yet, and their
+    // duration is only filled in once they end. A NULL :user_id means everybody's
+    // sessions, anything else only those of that user.
+    pub(crate) const LIST_CHAT_SESSIONS_QUERY: &str = "SELECT
+        s.chat_session_id, s.started_at, s.ended_at, s.duration, s.close_reason,
+        (SELECT COUNT(*) FROM chat_messages m WHERE m.chat_session_id = s.chat_session_id), s.user_id
+        FROM chat_sessions s JOIN cows c ON c.cow_id = s.cow_id
+        WHERE c.cow_name = :cow_name AND (:user_id IS NULL OR s.user_id = :user_id)
+        ORDER BY s.chat_session_id DESC LIMIT :limit OFFSET :offset;";
+    pub(crate) const COUNT_CHAT_SESSIONS_QUERY: &str = "SELECT COUNT(*)
+        FROM chat_sessions s JOIN cows c ON c.cow_id = s.cow_id
+        WHERE c.cow_name = :cow_name AND (:user_id IS NULL OR s.user_id = :user_id);";
+    // Sessions from before transcripts were recorded have neither timestamp, but
+    // they were only ever written once they had ended.
+    pub(crate) const CHAT_STATS_QUERY: &str = "SELECT COUNT(*), COALESCE(SUM(s.duration), 0)
+        FROM chat_sessions s JOIN cows c ON c.cow_id = s.cow_id
+        WHERE c.cow_name = :cow_name AND (s.ended_at IS NOT NULL OR s.started_at IS NULL);";
+    pub(crate) const CHECK_FOR_CHAT_SESSION_QUERY: &str = "SELECT 0 <> (SELECT COUNT(*) FROM chat_sessions
+        WHERE chat_session_id = :chat_session_id AND (:user_id IS NULL OR user_id = :user_id));";
+    pub(crate) const LIST_CHAT_MESSAGES_QUERY: &str = "SELECT chat_message_id, sender, body, sent_at
+        FROM chat_messages WHERE chat_session_id = :chat_session_id
+        ORDER BY chat_message_id ASC LIMIT :limit OFFSET :offset;";
+    pub(crate) const COUNT_CHAT_MESSAGES_QUERY: &str = "SELECT COUNT(*) FROM chat_messages
+        WHERE chat_session_id = :chat_session_id;";
+    pub(crate) const DELETE_COW_QUERY: &str = "DELETE FROM cows WHERE cow_name = :cow_name;";
+    // Keys are looked up by their hash, see crate::auth.
+    pub(crate) const FIND_USER_BY_KEY_QUERY: &str = "SELECT u.user_id, u.user_name, u.is_admin
+        FROM api_keys k JOIN users u ON u.user_id = k.user_id
+        WHERE k.key_hash = :key_hash AND k.revoked_at IS NULL;";
+    pub(crate) const INSERT_USER_QUERY: &str = "INSERT INTO
+        users (user_name, is_admin, created_at)
+        VALUES (:user_name, :is_admin, :created_at);";
+    pub(crate) const INSERT_API_KEY_QUERY: &str = "INSERT INTO
+        api_keys (user_id, key_hash, created_at)
+        VALUES ((SELECT user_id FROM users WHERE user_name = :user_name), :key_hash, :created_at);";
+    pub(crate) const REVOKE_API_KEYS_QUERY: &str = "UPDATE api_keys SET revoked_at = :revoked_at
+        WHERE revoked_at IS NULL AND user_id = (SELECT user_id FROM users WHERE user_name = :user_name);";
+    pub(crate) const LIST_USERS_QUERY: &str = "SELECT u.user_id, u.user_name, u.is_admin,
+        (SELECT COUNT(*) FROM api_keys k WHERE k.user_id = u.user_id AND k.revoked_at IS NULL)
+        FROM users u ORDER BY u.user_id;";
+    pub(crate) const CHECK_FOR_USER_QUERY: &str = "SELECT 0 <> (SELECT COUNT(*) FROM users WHERE user_name = :user_name);";
 }
 
 pub(crate) mod types {
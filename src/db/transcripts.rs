@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use r2d2_sqlite::rusqlite::named_params;
+
+use crate::db::{
+    queries::{FINISH_CHAT_SESSION, INSERT_CHAT_MESSAGE, INSERT_CHAT_SESSION},
+    types::MyPool,
+};
+
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_BATCH: usize = 100;
+// Failed writes in a row before the events are given up on. A write can fail
+// because the database is busy or no connection is free, which usually passes.
+pub(crate) const MAX_FLUSH_RETRIES: u32 = 5;
+
+// Chat actors must never wait on SQLite, so they don't write transcripts
+// themselves. They fire events at a TranscriptWriter, which runs on its own
+// Arbiter (thread), buffers the events and writes them out in batches, one
+// transaction per batch.
+//
+// Chats don't know their chat_session_id, since it only exists once the writer
+// has inserted the row. Instead, each chat gets a key that is unique within
+// this process, and the writer keeps track of which row belongs to which key.
+// Events from one chat arrive in the order they were sent, so the session row
+// is always inserted before its messages.
+//
+// A batch that can't be written goes back in front of the pending events and
+// is tried again with the next flush, so nothing is skipped or written out of
+// order. Only after MAX_FLUSH_RETRIES failures in a row are the events dropped.
+pub(crate) struct TranscriptWriter {
+    db_pool: MyPool,
+    pending: Vec<TranscriptEvent>,
+    session_ids: HashMap<u64, i64>,
+    failures: u32,
+    // Sessions whose events were dropped, since the writer started.
+    lost_sessions: usize,
+}
+
+// Statics are global variables. Atomics can be updated from any thread without a lock.
+static NEXT_SESSION_KEY: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn next_session_key() -> u64 {
+    NEXT_SESSION_KEY.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Sender {
+    User,
+    Cow,
+}
+
+impl AsRef<str> for Sender {
+    fn as_ref(&self) -> &str {
+        match self {
+            Sender::User => "user",
+            Sender::Cow => "cow",
+        }
+    }
+}
+
+// Actix messages are plain types with a #[derive(Message)]. The rtype says what
+// the recipient answers with, which is nothing for fire-and-forget events.
+// Timestamps are milliseconds since the Unix epoch, taken when things happened,
+// not when they get written.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub(crate) enum TranscriptEvent {
+    SessionStarted { key: u64, cow: String, started_at: u64, user_id: Option<i64> },
+    Message { key: u64, sender: Sender, body: String, sent_at: u64 },
+    SessionEnded { key: u64, ended_at: u64, duration_secs: u64, close_reason: String },
+}
+
+// Writes out everything buffered right away, and answers how things stand. Used
+// at shutdown.
+#[derive(Message)]
+#[rtype(result = "FlushStatus")]
+pub(crate) struct Flush;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, MessageResponse)]
+pub(crate) struct FlushStatus {
+    // Sessions whose end hasn't been written yet.
+    pub open: usize,
+    // Sessions whose events were dropped after too many failed writes.
+    pub lost: usize,
+}
+
+impl TranscriptWriter {
+    pub fn new(db_pool: MyPool) -> Self {
+        Self { db_pool, pending: Vec::new(), session_ids: HashMap::new(), failures: 0, lost_sessions: 0 }
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        // std::mem::take() swaps in an empty Vec and hands us the old one.
+        let mut batch = std::mem::take(&mut self.pending);
+        let Err(e) = self.write_batch(&batch) else {
+            self.failures = 0;
+            return;
+        };
+        self.failures += 1;
+        if self.failures <= MAX_FLUSH_RETRIES {
+            log::warn!("Failed to write {} transcript events, trying again ({} of {}): {}",
+                       batch.len(), self.failures, MAX_FLUSH_RETRIES, e);
+            // Whatever arrived in the meantime goes after the batch.
+            batch.append(&mut self.pending);
+            self.pending = batch;
+            return;
+        }
+        log::error!("Failed to write {} transcript events {} times in a row, they are lost: {}",
+                    batch.len(), self.failures, e);
+        self.failures = 0;
+        self.drop_batch(batch);
+    }
+
+    // Forgets the sessions in a batch that is given up on. Those that start in it
+    // never get a row, and those that end in it are not open anymore either way.
+    fn drop_batch(&mut self, batch: Vec<TranscriptEvent>) {
+        let mut lost: Vec<u64> = Vec::new();
+        for event in batch {
+            match event {
+                TranscriptEvent::SessionStarted { key, .. } => lost.push(key),
+                TranscriptEvent::SessionEnded { key, .. } => {
+                    if self.session_ids.remove(&key).is_some() {
+                        lost.push(key);
+                    }
+                },
+                TranscriptEvent::Message { .. } => {},
+            }
+        }
+        lost.sort_unstable();
+        lost.dedup();
+        self.lost_sessions += lost.len();
+    }
+
+    // Sessions that started but whose end isn't written yet, including those
+    // whose start is still waiting to be written.
+    fn open_sessions(&self) -> usize {
+        let starting = self.pending.iter()
+            .filter(|event| matches!(event, TranscriptEvent::SessionStarted { .. }))
+            .count();
+        self.session_ids.len() + starting
+    }
+
+    fn write_batch(&mut self, batch: &[TranscriptEvent]) -> anyhow::Result<()> {
+        let mut conn = self.db_pool.get()?;
+        let tx = conn.transaction()?;
+        // Ids of sessions inserted in this batch only count once the batch commits.
+        let mut new_ids: Vec<(u64, i64)> = Vec::new();
+        let mut ended: Vec<u64> = Vec::new();
+        for event in batch {
+            match event {
+                TranscriptEvent::SessionStarted { key, cow, started_at, user_id } => {
+                    tx.prepare_cached(INSERT_CHAT_SESSION)?
+                      .execute(named_params! {":cow_name": cow, ":started_at": started_at, ":user_id": user_id})?;
+                    new_ids.push((*key, tx.last_insert_rowid()));
+                },
+                TranscriptEvent::Message { key, sender, body, sent_at } => {
+                    if let Some(id) = self.session_id(*key, &new_ids) {
+                        tx.prepare_cached(INSERT_CHAT_MESSAGE)?.execute(named_params! {
+                            ":chat_session_id": id,
+                            ":sender": sender.as_ref(),
+                            ":body": body,
+                            ":sent_at": sent_at,
+                        })?;
+                    }
+                },
+                TranscriptEvent::SessionEnded { key, ended_at, duration_secs, close_reason } => {
+                    if let Some(id) = self.session_id(*key, &new_ids) {
+                        tx.prepare_cached(FINISH_CHAT_SESSION)?.execute(named_params! {
+                            ":chat_session_id": id,
+                            ":duration": duration_secs,
+                            ":ended_at": ended_at,
+                            ":close_reason": close_reason,
+                        })?;
+                    }
+                    ended.push(*key);
+                },
+            }
+        }
+        tx.commit()?;
+        self.session_ids.extend(new_ids);
+        for key in ended {
+            self.session_ids.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn session_id(&self, key: u64, new_ids: &[(u64, i64)]) -> Option<i64> {
+        new_ids.iter().find(|(k, _)| *k == key).map(|(_, id)| *id)
+               .or_else(|| self.session_ids.get(&key).copied())
+    }
+}
+
+impl Actor for TranscriptWriter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        context.run_interval(FLUSH_INTERVAL, |writer, _| writer.flush());
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.flush();
+    }
+}
+
+impl Handler<TranscriptEvent> for TranscriptWriter {
+    type Result = ();
+
+    fn handle(&mut self, event: TranscriptEvent, _: &mut Self::Context) {
+        self.pending.push(event);
+        // While writes are failing, only the timer retries, so that a pile of
+        // events doesn't turn into a retry for every new one.
+        if self.pending.len() >= MAX_BATCH && self.failures == 0 {
+            self.flush();
+        }
+    }
+}
+
+impl Handler<Flush> for TranscriptWriter {
+    type Result = FlushStatus;
+
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) -> FlushStatus {
+        self.flush();
+        FlushStatus { open: self.open_sessions(), lost: self.lost_sessions }
+    }
+}
@@ -1,41 +1,208 @@
-use std::fmt::{Display, Formatter, Result};
+use std::{
+    fmt::{Display, Formatter, Result},
+    time::Duration,
+};
 
-use actix_web::{HttpResponse, error::ResponseError};
+use actix_web::{
+    HttpRequest, HttpResponse,
+    error::{PathError, ResponseError},
+    http::{StatusCode, header::{RETRY_AFTER, WWW_AUTHENTICATE}},
+};
+use r2d2_sqlite::rusqlite;
+use serde::Serialize;
 
-// We use the `anyhow` crate to paper over diferences between various error and
-// result types, but we also want to send errors over the wire to the client.
-// This means we need to implement the ResponseError trait from Actix on the
-// generalized error type from Anyhow. You can't implement a foreign trait on a
-// foreign type in Rust. That's like adding unexpected new methods to Array in
-// JS. We declare a minimal wrapper type and implement the appropriate conversions
-// on this type instead.
-
-// Declaring the type. It's simple enough that Debug can be implemented on it
-// automatically.
+// Errors that can be sent over the wire to the client. Each variant says what
+// kind of failure happened, which determines the HTTP status code and the
+// machine-readable `code` that clients can match on. The Strings are
+// human-readable details.
+//
+// Internally, most functions return anyhow::Result, which can carry any error
+// type. Code that knows what went wrong wraps a CowError into the anyhow::Error
+// (`return Err(CowError::NotFound(...).into())`), and the conversion below
+// digs it back out at the handler boundary.
 #[derive(Debug)]
-pub(crate) struct CowError(anyhow::Error);
+pub(crate) enum CowError {
+    // The requested cow (or other resource) doesn't exist.
+    NotFound(String),
+    // The request clashes with the current state of the meadow.
+    Conflict(String),
+    // The meadow is full and can't take any more cows.
+    Capacity(String),
+    // The request was well-formed, but its values were out of bounds.
+    Validation(String),
+    // The request couldn't be parsed at all.
+    BadRequest(String),
+    // The request came without a valid API key.
+    Unauthorized(String),
+    // The API key is fine, but its user isn't allowed to do this.
+    Forbidden(String),
+    // The client is sending too much, and may try again after the Duration.
+    RateLimited(String, Duration),
+    // No database connection became available in time.
+    PoolExhausted(r2d2::Error),
+    // The server is shutting down and takes nothing new on.
+    ShuttingDown(String),
+    Database(rusqlite::Error),
+    Internal(anyhow::Error),
+}
+
+impl CowError {
+    // Stable identifiers for clients. Changing these is a breaking API change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CowError::NotFound(_) => "not_found",
+            CowError::Conflict(_) => "conflict",
+            CowError::Capacity(_) => "meadow_full",
+            CowError::Validation(_) => "validation_failed",
+            CowError::BadRequest(_) => "bad_request",
+            CowError::Unauthorized(_) => "unauthorized",
+            CowError::Forbidden(_) => "forbidden",
+            CowError::RateLimited(..) => "rate_limited",
+            CowError::PoolExhausted(_) => "pool_exhausted",
+            CowError::ShuttingDown(_) => "shutting_down",
+            CowError::Database(_) => "database_error",
+            CowError::Internal(_) => "internal_error",
+        }
+    }
+}
 
 // The Display trait controls what to_string() on this type returns.
-// We just proxy to the underlying error's implementation.
 impl Display for CowError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        // CowError is a tuple type, so its fields are numbered, not named.
-        write!(f, "{}", self.0)
+        // Several patterns can share a match arm if they bind the same names and types.
+        match self {
+            CowError::NotFound(msg) | CowError::Conflict(msg) | CowError::Capacity(msg)
+            | CowError::Validation(msg) | CowError::BadRequest(msg) | CowError::Unauthorized(msg)
+            | CowError::Forbidden(msg) | CowError::RateLimited(msg, _) | CowError::ShuttingDown(msg) => write!(f, "{}", msg),
+            CowError::PoolExhausted(e) => write!(f, "No database connection available: {}", e),
+            CowError::Database(e) => write!(f, "Database error: {}", e),
+            CowError::Internal(e) => write!(f, "{}", e),
+        }
     }
 }
 
-// Autoconversion between anyhow::Error and CowError.
+// Implementing std::error::Error (which just requires Debug and Display) is
+// what allows a CowError to travel inside an anyhow::Error.
+impl std::error::Error for CowError {}
+
+// Autoconversion from anyhow::Error to CowError. If a CowError was wrapped
+// somewhere down the call stack, we get it back unchanged. Bare database errors
+// are recognized too, and anything else is an internal error.
 impl From<anyhow::Error> for CowError {
     fn from(error: anyhow::Error) -> Self {
-        CowError(error)
+        // downcast() hands the original error back in Err if the type doesn't match,
+        // so we can try the next type.
+        match error.downcast::<CowError>() {
+            Ok(e) => e,
+            Err(error) => match error.downcast::<rusqlite::Error>() {
+                Ok(e) => CowError::Database(e),
+                Err(error) => CowError::Internal(error),
+            },
+        }
+    }
+}
+
+impl From<rusqlite::Error> for CowError {
+    fn from(error: rusqlite::Error) -> Self {
+        CowError::Database(error)
+    }
+}
+
+// r2d2 only fails to hand out a connection when none frees up before its timeout.
+impl From<r2d2::Error> for CowError {
+    fn from(error: r2d2::Error) -> Self {
+        CowError::PoolExhausted(error)
     }
 }
 
+// Extractor errors from actix-web-validator. Values that fail their validators
+// are a 422, input that can't even be parsed is a 400.
+impl From<actix_web_validator::Error> for CowError {
+    fn from(error: actix_web_validator::Error) -> Self {
+        match error {
+            actix_web_validator::Error::Validate(e) => {
+                // One "field: problem" line per failed check, sorted because the
+                // errors come out of a HashMap in no particular order.
+                let mut problems: Vec<String> = e.field_errors().iter().flat_map(|(field, errors)| {
+                    errors.iter().map(move |err| format!("{}: {}", field, err.code))
+                }).collect();
+                problems.sort();
+                CowError::Validation(problems.join("; "))
+            },
+            e => CowError::BadRequest(e.to_string()),
+        }
+    }
+}
+
+// Plugged into the JsonConfig and QueryConfig of actix-web-validator, so that
+// invalid requests are answered in the same format as every other error.
+pub(crate) fn validation_error_handler(error: actix_web_validator::Error, _: &HttpRequest) -> actix_web::Error {
+    CowError::from(error).into()
+}
+
+// Plugged into the PathConfig. A path segment of the wrong type, like a name
+// where an id belongs, names nothing, so it stays a 404, just with a proper body.
+pub(crate) fn path_error_handler(error: PathError, _: &HttpRequest) -> actix_web::Error {
+    CowError::NotFound(error.to_string()).into()
+}
+
+// The body of an error response, per RFC 7807 ("Problem Details for HTTP APIs").
+// `type` is a Rust keyword, so the field gets a raw identifier.
+#[derive(Serialize)]
+struct Problem<'a> {
+    r#type: &'a str,
+    title: &'a str,
+    status: u16,
+    detail: String,
+    code: &'a str,
+}
+
 // Finally, defining how the error should be sent over the wire.
-// This should be more complicated in a more complete app, with different
-// handling for different error types.
 impl ResponseError for CowError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CowError::NotFound(_) => StatusCode::NOT_FOUND,
+            CowError::Conflict(_) | CowError::Capacity(_) => StatusCode::CONFLICT,
+            CowError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            CowError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            CowError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            CowError::Forbidden(_) => StatusCode::FORBIDDEN,
+            CowError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            CowError::PoolExhausted(_) | CowError::ShuttingDown(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CowError::Database(_) | CowError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::InternalServerError().json(self.0.to_string())
+        let status = self.status_code();
+        // The text of a database or internal error is for us, not for clients, who
+        // could learn about the schema or the code from it. The request ID in the
+        // log line and the response ties the two together.
+        let detail = match self {
+            CowError::Database(_) | CowError::Internal(_) => {
+                log::error!("Answering with a {}: {}", status.as_u16(), self);
+                "Something went wrong on our side".to_string()
+            },
+            _ => self.to_string(),
+        };
+        let problem = Problem {
+            // "about:blank" means the problem is fully described by its status code,
+            // in which case the title should be the standard reason phrase.
+            r#type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail,
+            code: self.code(),
+        };
+        let mut response = HttpResponse::build(status);
+        // A 401 has to say how to authenticate (RFC 9110, section 11.6.1).
+        if let CowError::Unauthorized(_) = self {
+            response.insert_header((WWW_AUTHENTICATE, "Bearer"));
+        }
+        // Retry-After is in whole seconds, so the wait is rounded up.
+        if let CowError::RateLimited(_, wait) = self {
+            response.insert_header((RETRY_AFTER, wait.as_secs_f64().ceil().max(1.0).to_string()));
+        }
+        response.content_type("application/problem+json").json(problem)
     }
 }
@@ -0,0 +1,36 @@
+//! Chat with cows near you.
+//!
+//! A small [actix-web](https://docs.rs/actix-web) server that keeps a meadow of
+//! cows in SQLite, with REST endpoints for beckoning and releasing them and
+//! WebSocket chats for talking to them. [`CowchatServer`] runs it, standalone
+//! like the `cowchat` binary does, or with routes and middleware of your own.
+//! [`Cow`] and the other response types can also be used by clients of the API.
+
+// The library is where everything lives. main.rs is only the command line around
+// it. Modules that are `pub` here (and `pub` items in them) are the public API;
+// everything else is `pub(crate)` at most, so it can change freely.
+pub mod cli;
+pub mod config;
+pub mod logging;
+pub mod middleware;
+
+mod api;
+mod app;
+mod auth;
+mod catalog;
+mod db;
+mod errors;
+mod metrics;
+mod random;
+mod ratelimit;
+mod server;
+mod shutdown;
+mod tls;
+
+#[cfg(test)]
+mod tests;
+
+// `pub use` re-exports items from private modules, so that they can be named as
+// cowchat::Cow instead of cowchat::api::types::Cow.
+pub use api::types::{Cow, CowColor, CowListResponse, CowPageResponse, Personality};
+pub use server::CowchatServer;
@@ -0,0 +1,129 @@
+//! Log output with request IDs.
+//!
+//! Every request gets an ID, taken from its `X-Request-Id` header or made up,
+//! and sent back in the response's `X-Request-Id`. While the request is being
+//! handled (and while a chat it opened is running), [`request_id`] returns it,
+//! and the logger set up by [`init`] adds it to every line.
+
+use std::{
+    io::Write,
+    time::Instant,
+};
+
+use actix_web::{
+    Error,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+};
+use futures_util::future::LocalBoxFuture;
+use serde_json::json;
+
+use crate::auth::ACCESS_TOKEN_PARAM;
+use crate::config::{LogConfig, LogFormat};
+
+/// The header a request ID comes in and goes out in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// A task-local is like a thread-local, but belongs to one future instead of one
+// thread. Actix runs many requests on each worker thread, taking turns whenever
+// one of them waits, so a thread-local would mix up their IDs.
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Runs `f` as part of request `id`. Actors aren't futures of the request that
+// started them, so a chat calls this around its work to keep its ID in the logs.
+pub(crate) fn with_request_id<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    match id {
+        Some(id) => REQUEST_ID.sync_scope(id, f),
+        None => f(),
+    }
+}
+
+// IDs from clients end up in our logs, so only short, plain ones are taken over.
+// Anything else gets replaced, the same as a missing ID.
+fn incoming_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let plain = id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    (plain && !id.is_empty() && id.len() <= 128).then(|| id.to_string())
+}
+
+// The path and query, minus the value of an API key given in the query. Names
+// are compared decoded, the way auth reads them, so `access%5Ftoken` is caught too.
+pub(crate) fn redacted_uri(req: &ServiceRequest) -> String {
+    let query = req.query_string();
+    if query.is_empty() {
+        return req.path().to_string();
+    }
+    let query: Vec<&str> = query.split('&').map(|pair| {
+        match form_urlencoded::parse(pair.as_bytes()).next() {
+            Some((name, _)) if name == ACCESS_TOKEN_PARAM => "access_token=REDACTED",
+            _ => pair,
+        }
+    }).collect();
+    format!("{}?{}", req.path(), query.join("&"))
+}
+
+// Middleware that gives every request its ID and logs one line when the
+// response is ready. It replaces actix's Logger, whose line is written after the
+// body has been sent, which is after the request's task-local is gone.
+pub(crate) fn request_ids<S>(req: ServiceRequest, service: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    // Not the seeded generator from crate::random, so that IDs don't change
+    // which cows a seeded server beckons.
+    let id = incoming_id(&req).unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let started = Instant::now();
+    let peer = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "-".to_string());
+    let request_line = format!("{} {} {:?}", req.method(), redacted_uri(&req), req.version());
+    let response = REQUEST_ID.sync_scope(id.clone(), || service.call(req));
+    Box::pin(REQUEST_ID.scope(id.clone(), async move {
+        let outcome = response.await;
+        let status = match &outcome {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        log::info!("{} \"{}\" {} {:.3}ms", peer, request_line, status.as_u16(),
+                   started.elapsed().as_secs_f64() * 1000.0);
+        let mut response = outcome?;
+        // The ID passed the checks above (or is hex), so it is a valid header value.
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        Ok(response)
+    }))
+}
+
+/// Sets up the global logger: `RUST_LOG`-style filters from `config.level`,
+/// lines in `config.format`, and the request ID on lines logged for a request.
+/// Panics if a logger is already set up.
+pub fn init(config: &LogConfig) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&config.level);
+    match config.format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let request = request_id().map(|id| format!(" {}", id)).unwrap_or_default();
+            writeln!(buf, "[{} {:5} {}{}] {}", buf.timestamp_millis(), record.level(), record.target(), request, record.args())
+        }),
+        // One JSON object per line, for log pipelines. json! leaves out nothing,
+        // so the request_id is null on lines that don't belong to a request.
+        LogFormat::Json => builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "request_id": request_id(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use r2d2::{
+    HandleEvent,
+    event::{CheckoutEvent, TimeoutEvent},
+};
+
+// Everything /metrics reports, in Prometheus terms: counters only go up, gauges
+// go up and down, and histograms sort observations into buckets. The metric
+// types are handles to shared values (they clone like an Arc), so every copy of
+// Metrics updates the same numbers.
+//
+// Each server gets a registry of its own instead of the prometheus crate's
+// global one, so that several servers (like the ones the tests start) can live
+// in one process without their numbers getting mixed up.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    // Per route pattern, like "/cows/{cow_name}", never per actual path, which
+    // would make a new time series for every cow.
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub beckon_outcomes: IntCounterVec,
+    pub rate_limited: IntCounterVec,
+    // Set from the database whenever metrics are collected.
+    pub herd_size: IntGauge,
+    pub open_chat_sessions: IntGauge,
+    pub messages_per_session: Histogram,
+    pub chat_session_duration: HistogramVec,
+    pub pool_wait: Histogram,
+    pub pool_timeouts: IntCounter,
+    pub pool_connections: IntGauge,
+    pub pool_idle_connections: IntGauge,
+}
+
+// The label values of beckon_outcomes.
+pub(crate) const BECKON_SUCCESS: &str = "success";
+pub(crate) const BECKON_MEADOW_FULL: &str = "meadow_full";
+pub(crate) const BECKON_ERROR: &str = "error";
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("cowchat".to_string()), None).unwrap();
+        // The names and buckets are fixed, so registering can only fail on a
+        // duplicate name, which would be a bug right here.
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests to the /cows routes"),
+                &["method", "route", "status"],
+            ).unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time to answer requests to the /cows routes"),
+                &["method", "route"],
+            ).unwrap(),
+            beckon_outcomes: IntCounterVec::new(
+                Opts::new("beckon_total", "Beckon requests by outcome (success, meadow_full or error)"),
+                &["outcome"],
+            ).unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "Requests and chat messages turned away by the rate limits"),
+                &["kind"],
+            ).unwrap(),
+            herd_size: IntGauge::new("herd_size", "Cows in the meadow").unwrap(),
+            open_chat_sessions: IntGauge::new("chat_sessions_open", "Open WebSocket chats").unwrap(),
+            messages_per_session: Histogram::with_opts(
+                HistogramOpts::new("chat_messages_per_session", "Messages (from both sides) in finished chats")
+                    .buckets(vec![0.0, 2.0, 4.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0]),
+            ).unwrap(),
+            chat_session_duration: HistogramVec::new(
+                HistogramOpts::new("chat_session_duration_seconds", "Length of finished chats, by why they ended")
+                    .buckets(vec![1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0]),
+                &["close_reason"],
+            ).unwrap(),
+            pool_wait: Histogram::with_opts(
+                HistogramOpts::new("db_pool_wait_seconds", "Time spent waiting for a database connection")
+                    .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+            ).unwrap(),
+            pool_timeouts: IntCounter::new(
+                "db_pool_timeouts_total", "Times no database connection became available in time",
+            ).unwrap(),
+            pool_connections: IntGauge::new("db_pool_connections", "Open database connections").unwrap(),
+            pool_idle_connections: IntGauge::new("db_pool_idle_connections", "Idle database connections").unwrap(),
+            registry,
+        };
+        metrics.register_all();
+        metrics
+    }
+
+    fn register_all(&self) {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.http_requests.clone()),
+            Box::new(self.http_request_duration.clone()),
+            Box::new(self.beckon_outcomes.clone()),
+            Box::new(self.rate_limited.clone()),
+            Box::new(self.herd_size.clone()),
+            Box::new(self.open_chat_sessions.clone()),
+            Box::new(self.messages_per_session.clone()),
+            Box::new(self.chat_session_duration.clone()),
+            Box::new(self.pool_wait.clone()),
+            Box::new(self.pool_timeouts.clone()),
+            Box::new(self.pool_connections.clone()),
+            Box::new(self.pool_idle_connections.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    pub fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests.with_label_values(&[method, route, &status.to_string()]).inc();
+        self.http_request_duration.with_label_values(&[method, route]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_beckon(&self, outcome: &str) {
+        self.beckon_outcomes.with_label_values(&[outcome]).inc();
+    }
+
+    // `kind` is one of the LIMITED_* constants in crate::ratelimit.
+    pub fn record_rate_limited(&self, kind: &str) {
+        self.rate_limited.with_label_values(&[kind]).inc();
+    }
+
+    pub fn record_chat_session(&self, duration_secs: u64, messages: u64, close_reason: &str) {
+        self.chat_session_duration.with_label_values(&[close_reason]).observe(duration_secs as f64);
+        self.messages_per_session.observe(messages as f64);
+    }
+
+    // Everything, in the Prometheus text format.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    // Hooks the pool wait metrics into an r2d2 pool, see r2d2::Builder::event_handler().
+    pub fn pool_events(&self) -> Box<dyn HandleEvent> {
+        Box::new(PoolEvents { wait: self.pool_wait.clone(), timeouts: self.pool_timeouts.clone() })
+    }
+}
+
+// r2d2 calls this whenever a connection is handed out, or isn't in time.
+#[derive(Debug)]
+struct PoolEvents {
+    wait: Histogram,
+    timeouts: IntCounter,
+}
+
+impl HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.wait.observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, event: TimeoutEvent) {
+        self.wait.observe(event.timeout().as_secs_f64());
+        self.timeouts.inc();
+    }
+}
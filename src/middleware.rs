@@ -0,0 +1,101 @@
+//! Middleware hooks for applications that embed the server, see
+//! [`CowchatServer::middleware`](crate::CowchatServer::middleware).
+
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    Error,
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+use futures_util::future::LocalBoxFuture;
+
+// Actix middleware is usually a pair of types (a Transform that makes a Service
+// for every server thread), and every wrap() changes the type of the App. That
+// doesn't work for a list of hooks that is only known at runtime, so all hooks
+// are run by the one Transform below. Each hook gets the request and a Next that
+// runs the rest of the list, and finally the app itself.
+
+// A hook with its future boxed, so that hooks of different types fit in one Vec.
+// Hooks are shared by all server threads, hence Send + Sync.
+pub(crate) type MiddlewareHook =
+    Arc<dyn Fn(ServiceRequest, Next) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Send + Sync>;
+
+// The app behind the hooks, with its response body boxed, so that Next doesn't
+// need to know the app's type.
+type Inner = Rc<dyn Fn(ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>>;
+
+/// The rest of the middleware chain, handed to every hook along with the request.
+pub struct Next {
+    hooks: Rc<[MiddlewareHook]>,
+    index: usize,
+    inner: Inner,
+}
+
+impl Next {
+    /// Passes the request on to the next hook, or to the server's own routes if
+    /// this was the last one, and returns their response.
+    pub fn call(self, req: ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> {
+        match self.hooks.get(self.index).cloned() {
+            Some(hook) => hook(req, Next { index: self.index + 1, ..self }),
+            None => (self.inner)(req),
+        }
+    }
+}
+
+pub(crate) struct Hooks(Vec<MiddlewareHook>);
+
+impl Hooks {
+    pub fn new(hooks: Vec<MiddlewareHook>) -> Self {
+        Self(hooks)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Hooks
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = HooksService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let app = service.clone();
+        let inner: Inner = Rc::new(move |req| {
+            let app = app.clone();
+            Box::pin(async move { app.call(req).await.map(ServiceResponse::map_into_boxed_body) })
+        });
+        ready(Ok(HooksService { service, hooks: self.0.iter().cloned().collect(), inner }))
+    }
+}
+
+pub(crate) struct HooksService<S> {
+    // Only kept for poll_ready(), requests go through `inner`.
+    service: Rc<S>,
+    hooks: Rc<[MiddlewareHook]>,
+    inner: Inner,
+}
+
+impl<S, B> Service<ServiceRequest> for HooksService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        Next { hooks: self.hooks.clone(), index: 0, inner: self.inner.clone() }.call(req)
+    }
+}
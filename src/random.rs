@@ -0,0 +1,82 @@
+use std::{
+    future::{ready, Ready},
+    sync::Mutex,
+};
+
+use actix_web::{
+    dev::Payload, FromRequest, HttpRequest,
+    http::header::HeaderName,
+    web::Data,
+};
+use rand::{
+    rngs::StdRng, SeedableRng,
+};
+
+use crate::config::RandomConfig;
+use crate::errors::CowError;
+
+// Lets tests pick the seed of a single request, if the server runs in test mode.
+pub(crate) static SEED_HEADER: HeaderName = HeaderName::from_static("x-cowchat-seed");
+
+// Where every request gets its randomness from. Each request gets a generator of
+// its own, which is seeded from this one, so with a configured seed the server
+// makes the same cows and says the same things every time it is sent the same
+// requests in the same order.
+pub(crate) struct RngSource {
+    root: Mutex<StdRng>,
+    test_mode: bool,
+}
+
+impl RngSource {
+    pub fn new(config: &RandomConfig) -> Self {
+        let root = match config.seed {
+            Some(seed) => {
+                log::info!("Random generation is seeded with {}", seed);
+                StdRng::seed_from_u64(seed)
+            },
+            None => StdRng::from_entropy(),
+        };
+        if config.test_mode {
+            log::warn!("Test mode is on, requests can choose their own seed with {}", SEED_HEADER);
+        }
+        Self { root: Mutex::new(root), test_mode: config.test_mode }
+    }
+
+    // In test mode, a request with an X-Cowchat-Seed header gets a generator seeded
+    // with it, independent of every other request. Otherwise the header is ignored.
+    fn for_request(&self, req: &HttpRequest) -> Result<StdRng, CowError> {
+        if self.test_mode {
+            if let Some(value) = req.headers().get(&SEED_HEADER) {
+                let seed = value.to_str().ok().and_then(|value| value.trim().parse::<u64>().ok())
+                    .ok_or_else(|| CowError::BadRequest(format!("{} must be an unsigned integer", SEED_HEADER)))?;
+                return Ok(StdRng::seed_from_u64(seed));
+            }
+        }
+        // from_rng() only fails for generators that can fail, which StdRng can't.
+        Ok(StdRng::from_rng(&mut *self.root.lock().unwrap()).unwrap())
+    }
+}
+
+// An extractor, like Path or Json: a handler that takes a RequestRng argument
+// gets a generator set up for its request by from_request() below.
+pub(crate) struct RequestRng(StdRng);
+
+impl RequestRng {
+    pub fn into_inner(self) -> StdRng {
+        self.0
+    }
+}
+
+impl FromRequest for RequestRng {
+    type Error = CowError;
+    // Nothing to wait for, so the future is ready right away.
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let outcome = match req.app_data::<Data<RngSource>>() {
+            Some(source) => source.for_request(req).map(RequestRng),
+            None => Err(CowError::Internal(anyhow::anyhow!("No RngSource in app data"))),
+        };
+        ready(outcome)
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    Error, HttpMessage,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::auth::User;
+use crate::config::RateLimitConfig;
+use crate::errors::CowError;
+use crate::metrics::Metrics;
+
+// Token buckets: every client has a bucket that holds up to `burst` tokens and
+// refills at `per_minute` tokens a minute. Each request (or chat message) takes
+// a token, and one that finds the bucket empty is turned away, with how long
+// until the next token arrives.
+//
+// Requests to /cows, /sessions and /admin share one bucket per client. A client
+// is the user behind the API key if there is one, so that a user's keys share a
+// budget, and otherwise the IP address the request came from. Requests that
+// fail auth are charged to their IP address, and an IP address whose bucket is
+// empty is refused before auth even looks at its key, so guessing keys is as
+// slow as anything else. Every chat has a bucket of its own for the messages
+// its client sends, see ChatThrottle.
+
+// How often (in requests) buckets that have refilled completely are thrown
+// away. A full bucket is the same as no bucket, so nothing is lost.
+const PRUNE_EVERY: u64 = 1024;
+
+// The label values of Metrics::rate_limited.
+pub(crate) const LIMITED_REQUEST: &str = "request";
+pub(crate) const LIMITED_CHAT_MESSAGE: &str = "chat_message";
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Budget {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Budget {
+    fn tokens_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    // Fractions of a token count, so that slow rates refill smoothly.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn full(budget: Budget, now: Instant) -> Self {
+        Self { tokens: budget.burst as f64, updated: now }
+    }
+
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.tokens_per_sec()).min(budget.burst as f64);
+        self.updated = now;
+    }
+
+    // Takes a token, or says how long until there is one to take.
+    pub fn take(&mut self, budget: Budget, now: Instant) -> Result<(), Duration> {
+        self.refill(budget, now);
+        self.available(budget)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    // Whether there is a token to take, as of the last refill.
+    fn available(&self, budget: Budget) -> Result<(), Duration> {
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / budget.tokens_per_sec()))
+        }
+    }
+
+    fn is_full(&self, budget: Budget, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * budget.tokens_per_sec() >= budget.burst as f64
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<String, TokenBucket>,
+    checks: u64,
+}
+
+// The buckets for requests, shared by every server thread. Each check holds the
+// lock only for a bit of arithmetic.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    enabled: bool,
+    budget: Budget,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            budget: Budget { burst: config.request_burst, per_minute: config.requests_per_minute },
+            buckets: Arc::default(),
+        }
+    }
+
+    // Takes a token from `client`'s bucket, or says how long until there is one.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        self.with_bucket(client, now, |bucket, budget| bucket.take(budget, now))
+    }
+
+    // Like check(), but leaves the token in the bucket.
+    pub fn peek(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        self.with_bucket(client, now, |bucket, budget| {
+            bucket.refill(budget, now);
+            bucket.available(budget)
+        })
+    }
+
+    fn with_bucket<R>(&self, client: &str, now: Instant, f: impl FnOnce(&mut TokenBucket, Budget) -> R) -> R {
+        // A panic while the lock was held can't have left the buckets half-updated
+        // in a way that matters, so a poisoned lock is used all the same.
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        buckets.checks += 1;
+        if buckets.checks.is_multiple_of(PRUNE_EVERY) {
+            let budget = self.budget;
+            buckets.by_client.retain(|_, bucket| !bucket.is_full(budget, now));
+        }
+        let bucket = buckets.by_client.entry(client.to_string()).or_insert_with(|| TokenBucket::full(self.budget, now));
+        f(bucket, self.budget)
+    }
+}
+
+// Who a request counts against.
+fn client_key(req: &ServiceRequest) -> String {
+    match req.extensions().get::<User>() {
+        Some(user) => format!("user:{}", user.id),
+        None => ip_key(req),
+    }
+}
+
+fn ip_key(req: &ServiceRequest) -> String {
+    // Not connection_info().realip_remote_addr(), which believes whatever the
+    // Forwarded and X-Forwarded-For headers say, so anyone could pick a new
+    // bucket for every request.
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
+
+// Middleware for the scopes behind auth, in the style of auth::require(). It has
+// to be inside the auth middleware, so that the User is known. Turned off, it
+// lets everything through.
+pub(crate) fn limit_requests<S>(limiter: RateLimiter, metrics: Metrics)
+    -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    move |req, service| {
+        if !limiter.enabled {
+            return Box::pin(service.call(req));
+        }
+        let client = client_key(&req);
+        match limiter.check(&client, Instant::now()) {
+            Ok(()) => Box::pin(service.call(req)),
+            Err(wait) => throttled(req, &limiter, &metrics, &client, wait),
+        }
+    }
+}
+
+// Middleware for the same scopes, but outside the auth middleware. It refuses
+// requests from an IP address whose bucket is empty, and charges every request
+// that auth turned away with a 401 to its IP address.
+pub(crate) fn limit_failed_auth<S>(limiter: RateLimiter, metrics: Metrics)
+    -> impl Fn(ServiceRequest, &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> + Clone
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    move |req, service| {
+        if !limiter.enabled {
+            return Box::pin(service.call(req));
+        }
+        let client = ip_key(&req);
+        if let Err(wait) = limiter.peek(&client, Instant::now()) {
+            return throttled(req, &limiter, &metrics, &client, wait);
+        }
+        let limiter = limiter.clone();
+        let response = service.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                // The request is answered already, so all that's left is the charge.
+                let _ = limiter.check(&client, Instant::now());
+            }
+            Ok(response)
+        })
+    }
+}
+
+// The 429 for a request over its budget.
+fn throttled(req: ServiceRequest, limiter: &RateLimiter, metrics: &Metrics, client: &str, wait: Duration)
+    -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+{
+    log::info!("Throttled {} {} for {}, who can try again in {:.1}s",
+               req.method(), req.path(), client, wait.as_secs_f64());
+    metrics.record_rate_limited(LIMITED_REQUEST);
+    let error = CowError::RateLimited(format!("Too many requests, at most {} a minute", limiter.budget.per_minute), wait);
+    let response = req.error_response(error);
+    Box::pin(async move { Ok(response) })
+}
+
+// What a chat should do with a message from its client.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allowed,
+    // Drop the message and tell the client to wait this long.
+    Throttled(Duration),
+    // The client kept going after enough warnings. Close the chat.
+    Exceeded,
+}
+
+// The message budget of one chat. Every throttled message gets a notice, and a
+// chat that sends more than `warnings` throttled messages in a row is closed.
+// A message that gets through starts the count over.
+pub(crate) struct ChatThrottle {
+    budget: Budget,
+    bucket: TokenBucket,
+    warnings: u32,
+    strikes: u32,
+}
+
+impl ChatThrottle {
+    // None if rate limiting is turned off.
+    pub fn new(config: &RateLimitConfig, now: Instant) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let budget = Budget { burst: config.chat_message_burst, per_minute: config.chat_messages_per_minute };
+        Some(Self { budget, bucket: TokenBucket::full(budget, now), warnings: config.chat_throttle_warnings, strikes: 0 })
+    }
+
+    pub fn per_minute(&self) -> u32 {
+        self.budget.per_minute
+    }
+
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        match self.bucket.take(self.budget, now) {
+            Ok(()) => {
+                self.strikes = 0;
+                Verdict::Allowed
+            },
+            Err(_) if self.strikes >= self.warnings => Verdict::Exceeded,
+            Err(wait) => {
+                self.strikes += 1;
+                Verdict::Throttled(wait)
+            },
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    future::Future,
+    sync::Arc,
+};
+
+use actix_web::{
+    App, Error, HttpServer,
+    dev::{Server, ServiceRequest, ServiceResponse},
+    web::{Data, ServiceConfig, to},
+};
+use anyhow::Context;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::app::{AppState, Extras, build_app};
+use crate::catalog::{self, CatalogStore};
+use crate::config::Config;
+use crate::db::migrations::migrate_up;
+use crate::metrics::Metrics;
+use crate::middleware::Next;
+use crate::shutdown::{HTTP_STOP_TIMEOUT_SECS, shutdown_on_signal};
+use crate::tls::{self, CertStore, redirect_to_https};
+
+// r2d2's own default for the pool size, kept as long as there are fewer workers.
+const DEFAULT_POOL_SIZE: u32 = 10;
+
+/// Builds and runs a cowchat server.
+///
+/// The server migrates its database, loads the catalog and then serves the
+/// `/cows`, `/sessions` and `/admin` routes, plus whatever routes and middleware
+/// were added here. It must be started from within an Actix system, e.g. under
+/// `#[actix_web::main]`. On SIGTERM or SIGINT, it closes open chats and waits up
+/// to `server.shutdown_grace_secs` for them to be recorded, then gives requests
+/// still running a couple of seconds more before it stops.
+///
+/// ```no_run
+/// use cowchat::{CowchatServer, config::Config};
+///
+/// #[actix_web::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let mut config = Config::default();
+///     config.server.port = 8080;
+///     CowchatServer::new(config)
+///         .routes(|cfg| {
+///             cfg.route("/hello", actix_web::web::get().to(|| async { "Moo!" }));
+///         })
+///         .run()
+///         .await
+/// }
+/// ```
+pub struct CowchatServer {
+    config: Config,
+    pool: Option<Pool<SqliteConnectionManager>>,
+    // Visible to the tests, which build the app without starting a server.
+    pub(crate) extras: Extras,
+}
+
+impl CowchatServer {
+    /// A server with the given configuration and nothing added.
+    pub fn new(config: Config) -> Self {
+        Self { config, pool: None, extras: Extras::default() }
+    }
+
+    /// Uses this connection pool instead of opening `database.path`. Pending
+    /// migrations are applied to it on startup, like to any other database.
+    /// The `/metrics` pool wait times are only measured for the server's own pool.
+    pub fn pool(mut self, pool: Pool<SqliteConnectionManager>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Adds routes to the server. They are registered after the built-in ones,
+    /// so paths under `/cows` are taken. Can be called more than once.
+    pub fn routes<F>(mut self, routes: F) -> Self
+    where
+        F: Fn(&mut ServiceConfig) + Send + Sync + 'static,
+    {
+        self.extras.routes.push(Arc::new(routes));
+        self
+    }
+
+    /// Adds a middleware hook, which sees every request before the server's
+    /// routes do and every response after. The hook passes the request on with
+    /// [`Next::call`], or answers it by itself. Hooks run in the order they were
+    /// added, so the first one sees requests first and responses last.
+    pub fn middleware<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(ServiceRequest, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ServiceResponse, Error>> + 'static,
+    {
+        self.extras.middleware.push(Arc::new(move |req, next| Box::pin(hook(req, next))));
+        self
+    }
+
+    /// Sets everything up and starts listening. The returned [`Server`] runs
+    /// when it is awaited (or spawned), and its handle can stop it.
+    pub fn start(self) -> anyhow::Result<Server> {
+        let Self { config, pool, extras } = self;
+        config.validate()?;
+        let metrics = Metrics::new();
+
+        // A match rather than unwrap_or_else(), because a `?` in a closure would
+        // return from the closure instead of from start().
+        let pool = match pool {
+            Some(pool) => pool,
+            // r2d2 panics if min_idle is above max_size, whose default is 10, so the
+            // pool grows along with the workers.
+            None => Pool::builder()
+                .min_idle(Some(config.server.workers)) // This arg can also be Option::None, hence Option::Some(N).
+                .max_size(config.server.workers.max(DEFAULT_POOL_SIZE))
+                .event_handler(metrics.pool_events())
+                .build(SqliteConnectionManager::file(&config.database.path))
+                .with_context(|| format!("Could not open {}", config.database.path))?,
+        };
+        // Bring the schema up to date before serving anything. A database that
+        // doesn't match this build's migrations is not something to guess about.
+        let mut conn = pool.get()?;
+        migrate_up(&mut conn).context("Could not migrate the database")?;
+        drop(conn);
+
+        let catalog = CatalogStore::load(&config.catalog).context("Could not load catalog")?;
+        actix_web::rt::spawn(catalog::reload_on_sighup(catalog.clone()));
+
+        // We create the DB connection pool, the chat actors and the rest of the shared
+        // state once, and issue references to it to each copy of the multithreaded
+        // application.
+        let state = AppState::new(pool, catalog, &config, metrics);
+        let chat_services = state.chat_services().clone();
+
+        // This closure initializes each server thread with the application logic.
+        // Each app thread is self-contained, so it "eats" all references it needs
+        // from the parent scope instead of just referring to them.
+        let app_factory = move || build_app(&state, &extras);
+
+        // A tuple.
+        let host_port = (config.server.host.as_str(), config.server.port);
+
+        let server = HttpServer::new(app_factory)
+            // no automatic conversions between numeric types in Rust
+            .workers(config.server.workers as usize)
+            // Signals are handled by crate::shutdown instead, which closes the chats first.
+            .disable_signals()
+            .shutdown_timeout(HTTP_STOP_TIMEOUT_SECS);
+
+        // if/else is an expression, so both branches produce the bound server.
+        let server = if config.tls.enabled {
+            let acceptor = start_tls(&config).context("Could not set up TLS")?;
+            server.bind_openssl(host_port, acceptor)
+        } else {
+            server.bind(host_port)
+        };
+        let server = server.with_context(|| format!("Could not listen on {}:{}", host_port.0, host_port.1))?.run();
+        actix_web::rt::spawn(shutdown_on_signal(server.handle(), chat_services, config.server.shutdown_grace()));
+        Ok(server)
+    }
+
+    /// Starts the server and runs it until it is stopped.
+    pub async fn run(self) -> anyhow::Result<()> {
+        // Awaiting the server (which is basically a Promise) is what makes it run.
+        self.start()?.await?;
+        Ok(())
+    }
+}
+
+// Loads the certificate, starts listening for SIGHUP to reload it, and starts the
+// plain-HTTP redirect server if one is configured.
+fn start_tls(config: &Config) -> anyhow::Result<openssl::ssl::SslAcceptorBuilder> {
+    let store = CertStore::load(&config.tls)?;
+    let acceptor = store.acceptor()?;
+    // spawn() runs a future in the background on the current Actix runtime.
+    actix_web::rt::spawn(tls::reload_on_sighup(store));
+
+    if let Some(http_port) = config.tls.redirect_http_port {
+        let https_port = Data::new(config.server.port);
+        let redirect_server = HttpServer::new(move || {
+            App::new().app_data(https_port.clone())
+                      .default_service(to(redirect_to_https))
+        }).workers(1)
+          .bind((config.server.host.as_str(), http_port))?
+          .run();
+        actix_web::rt::spawn(redirect_server);
+        log::info!("Redirecting plain HTTP on port {} to HTTPS on port {}", http_port, config.server.port);
+    }
+    Ok(acceptor)
+}
@@ -0,0 +1,107 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::dev::ServerHandle;
+use futures_util::future::{Either, select};
+
+use crate::api::rooms::CloseAll;
+use crate::api::websockets::ChatServices;
+use crate::db::transcripts::{Flush, FlushStatus};
+
+// How often the transcript writer is asked whether it's done yet.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long the HTTP server waits for requests still running once the chats are
+// drained (HttpServer::shutdown_timeout()). The grace period is spent on the
+// chats already, so this is short and fixed, and a shutdown takes at most the
+// grace period plus this.
+pub(crate) const HTTP_STOP_TIMEOUT_SECS: u64 = 2;
+
+// actix-web can stop gracefully by itself, but it only waits for requests, and
+// to a server a chat is one request that never ends. When the wait is over, the
+// chat actors are simply dropped, before stopped() could record their sessions.
+// So the server handles SIGTERM and SIGINT itself:
+//
+// 1. New chats are refused, with a 503.
+// 2. Every open chat is closed with a "going away" Close frame, which makes it
+//    stop and send its session to the transcript writer.
+// 3. The writer is asked to write everything it has, until no session is left
+//    open or the grace period is over.
+// 4. Only then does the HTTP server stop.
+
+// Set once shutdown has started. Shared by every server thread.
+#[derive(Clone, Default)]
+pub(crate) struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub fn begin(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DrainReport {
+    // Chats that were open when shutdown began.
+    pub closed: usize,
+    // Sessions recorded as finished, and sessions that weren't by the end of the grace period.
+    pub flushed: usize,
+    pub dropped: usize,
+}
+
+// Steps 1 to 3. Returns early once every session is written.
+pub(crate) async fn drain(services: &ChatServices, grace: Duration) -> DrainReport {
+    services.draining.begin();
+    // The actors only fail to answer if they are gone already, and then there's nothing to wait for.
+    let closed = services.rooms.send(CloseAll).await.unwrap_or(0);
+    let deadline = Instant::now() + grace;
+    let before = services.transcripts.send(Flush).await.unwrap_or_default();
+    let mut status = before;
+    while status.open > 0 && Instant::now() < deadline {
+        actix_web::rt::time::sleep(FLUSH_POLL_INTERVAL).await;
+        status = services.transcripts.send(Flush).await.unwrap_or_default();
+    }
+    // Sessions the writer gave up on during the drain were not recorded either.
+    let FlushStatus { open, lost } = status;
+    let dropped = open + lost.saturating_sub(before.lost);
+    // Sessions can be open without being in a room, if they stopped before
+    // joining, so `dropped` can be more than `closed`.
+    DrainReport { closed, flushed: closed.saturating_sub(dropped), dropped }
+}
+
+// Waits for SIGTERM or SIGINT, then shuts the server down as described above.
+pub(crate) async fn shutdown_on_signal(server: ServerHandle, services: ChatServices, grace: Duration) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let (mut terminate, mut interrupt) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("Could not listen for SIGTERM and SIGINT, the server can't shut down gracefully: {}", e);
+            return;
+        },
+    };
+    // select() finishes with whichever future finishes first.
+    let name = match select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    };
+    log::info!("Got {}, closing chats and waiting up to {} seconds for their sessions to be recorded",
+               name, grace.as_secs());
+    let report = drain(&services, grace).await;
+    if report.dropped == 0 {
+        log::info!("Closed {} chat(s) and recorded all their sessions", report.closed);
+    } else {
+        log::warn!("Closed {} chat(s), recorded {} session(s) and dropped {} that weren't written in time",
+                   report.closed, report.flushed, report.dropped);
+    }
+    // Graceful, for the requests still running, but only for HTTP_STOP_TIMEOUT_SECS.
+    // No new chats can have started.
+    server.stop(true).await;
+}
@@ -0,0 +1,157 @@
+use actix_web::test::{self, TestRequest};
+use awc::ws::{Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+
+use crate::app::AppState;
+use crate::auth::{create_user, revoke_keys};
+use crate::config::{AuthConfig, Config};
+use crate::db::types::MyPool;
+use crate::metrics::Metrics;
+use crate::tests::chat::finished_session;
+use crate::tests::{call, memory_pool, migrated, test_app, test_config, test_state_with};
+
+fn auth_config() -> Config {
+    Config { auth: AuthConfig { enabled: true }, ..test_config() }
+}
+
+struct Farm {
+    state: AppState,
+    pool: MyPool,
+    admin_key: String,
+    user_key: String,
+}
+
+// A state with an admin and an ordinary user, and their keys.
+fn farm() -> Farm {
+    let config = auth_config();
+    let pool = migrated(memory_pool());
+    let mut conn = pool.get().unwrap();
+    let admin_key = create_user(&mut conn, "farmer", true).unwrap();
+    let user_key = create_user(&mut conn, "visitor", false).unwrap();
+    // The pool has only the one connection.
+    drop(conn);
+    let state = test_state_with(&config, pool.clone(), Metrics::new());
+    Farm { state, pool, admin_key, user_key }
+}
+
+fn with_key(request: TestRequest, key: &str) -> TestRequest {
+    request.insert_header(("Authorization", format!("Bearer {}", key)))
+}
+
+#[actix_web::test]
+async fn requests_without_a_valid_key_are_refused() {
+    let Farm { state, pool, user_key: user, .. } = farm();
+    let app = test::init_service(test_app(&state)).await;
+
+    let response = test::call_service(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(response.status(), 401);
+    assert_eq!(response.headers().get("www-authenticate").unwrap(), "Bearer");
+    for header in ["Bearer cowchat_nope", "Basic dXNlcjpwYXNz", user.as_str()] {
+        let request = TestRequest::get().uri("/cows/count").insert_header(("Authorization", header));
+        let (status, problem) = call(&app, request.to_request()).await;
+        assert_eq!((status, &problem["code"]), (401, &json!("unauthorized")), "{}", header);
+    }
+    // Keys in the URL are only for chats.
+    let request = TestRequest::get().uri(&format!("/cows/count?access_token={}", user));
+    assert_eq!(call(&app, request.to_request()).await.0, 401);
+
+    assert_eq!(call(&app, with_key(TestRequest::get().uri("/cows/count"), &user).to_request()).await, (200, json!(0)));
+    for scheme in ["bearer", "BEARER"] {
+        let request = TestRequest::get().uri("/cows/count").insert_header(("Authorization", format!("{} {}", scheme, user)));
+        assert_eq!(call(&app, request.to_request()).await.0, 200, "{}", scheme);
+    }
+    // The probes stay open.
+    assert_eq!(call(&app, TestRequest::get().uri("/healthz").to_request()).await.0, 200);
+
+    revoke_keys(&pool.get().unwrap(), "visitor").unwrap();
+    assert_eq!(call(&app, with_key(TestRequest::get().uri("/cows/count"), &user).to_request()).await.0, 401);
+}
+
+#[actix_web::test]
+async fn only_admins_release_cows_and_use_admin_routes() {
+    let Farm { state, admin_key: admin, user_key: user, .. } = farm();
+    let app = test::init_service(test_app(&state)).await;
+    let beckon = TestRequest::post().uri("/cows/beckon").set_json(json!({ "count": 2 }));
+    let (_, body) = call(&app, with_key(beckon, &user).to_request()).await;
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+
+    let release = || TestRequest::post().uri("/cows/release").set_json(json!({ "count": 1 }));
+    let delete = || TestRequest::delete().uri(&format!("/cows/{}", cow));
+    let reload = || TestRequest::post().uri("/admin/catalog/reload");
+    for request in [release(), delete(), reload()] {
+        let (status, problem) = call(&app, with_key(request, &user).to_request()).await;
+        assert_eq!((status, &problem["code"]), (403, &json!("forbidden")));
+    }
+    // Ordinary users can still change a cow.
+    let patch = TestRequest::patch().uri(&format!("/cows/{}", cow)).set_json(json!({ "age": 7 }));
+    assert_eq!(call(&app, with_key(patch, &user).to_request()).await.0, 200);
+
+    assert_eq!(call(&app, with_key(delete(), &admin).to_request()).await.0, 200);
+    assert_eq!(call(&app, with_key(release(), &admin).to_request()).await.0, 200);
+    // The built-in catalog can't be reloaded, but the admin got past the check.
+    assert_eq!(call(&app, with_key(reload(), &admin).to_request()).await.0, 409);
+}
+
+#[actix_web::test]
+async fn chats_take_the_key_from_the_query_and_record_the_user() {
+    let Farm { state, pool, user_key: user, .. } = farm();
+    let server = actix_test::start(move || test_app(&state));
+    let bearer = format!("Bearer {}", user);
+    let get = |path: String| server.get(path).insert_header(("Authorization", bearer.clone()));
+    let mut response = server.post("/cows/beckon").insert_header(("Authorization", bearer.clone()))
+        .send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+
+    let refused = awc::Client::new().ws(server.url(&format!("/cows/chat/{}", cow))).connect().await;
+    assert!(refused.is_err(), "A chat without a key was let in");
+
+    let url = server.url(&format!("/cows/chat/{}?access_token={}", cow, user));
+    let (_, mut chat) = awc::Client::new().ws(url).connect().await.unwrap();
+    chat.send(Message::Text("Who am I?".into())).await.unwrap();
+    assert!(matches!(chat.next().await, Some(Ok(Frame::Text(_)))));
+    let occupants: Value = get(format!("/cows/{}/occupants", cow)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(occupants["occupants"][0]["name"], "visitor");
+    chat.send(Message::Close(None)).await.unwrap();
+
+    let visitor_id: i64 = pool.get().unwrap()
+        .query_row("SELECT user_id FROM users WHERE user_name = 'visitor'", [], |row| row.get(0))
+        .unwrap();
+    let session = finished_session(&server, &cow, Some(&user)).await;
+    assert_eq!(session["user_id"], visitor_id);
+}
+
+#[actix_web::test]
+async fn users_only_read_their_own_chats() {
+    let Farm { state, admin_key: admin, user_key: user, .. } = farm();
+    let server = actix_test::start(move || test_app(&state));
+    let mut response = server.post("/cows/beckon").insert_header(("Authorization", format!("Bearer {}", admin)))
+        .send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+
+    let url = server.url(&format!("/cows/chat/{}?access_token={}", cow, admin));
+    let (_, mut chat) = awc::Client::new().ws(url).connect().await.unwrap();
+    chat.send(Message::Text("Just between us".into())).await.unwrap();
+    assert!(matches!(chat.next().await, Some(Ok(Frame::Text(_)))));
+    chat.send(Message::Close(None)).await.unwrap();
+    let session = finished_session(&server, &cow, Some(&admin)).await;
+
+    let read = |path: String, key: &str| {
+        server.get(path).insert_header(("Authorization", format!("Bearer {}", key))).send()
+    };
+    let transcript = format!("/sessions/{}/messages", session["id"]);
+    let mut response = read(transcript.clone(), &user).await.unwrap();
+    assert_eq!(response.status(), 404);
+    let problem: Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "not_found");
+    let mut response = read(format!("/cows/{}/sessions", cow), &user).await.unwrap();
+    let sessions: Value = response.json().await.unwrap();
+    assert_eq!((sessions["total"].clone(), sessions["sessions"].clone()), (json!(0), json!([])));
+
+    let mut response = read(transcript, &admin).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let messages: Value = response.json().await.unwrap();
+    assert_eq!(messages["messages"][0]["body"], "Just between us");
+}
@@ -0,0 +1,191 @@
+use std::time::{Duration, Instant};
+
+use actix_codec::Framed;
+use actix_test::TestServer;
+use awc::{
+    BoxedSocket,
+    ws::{Codec, Frame, Message},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+
+use crate::api::protocol::JSON_PROTOCOL;
+use crate::tests::{test_app, test_config, test_state};
+
+type Chat = Framed<BoxedSocket, Codec>;
+
+// Chats need a real server to talk to, so these tests start one on a free port.
+fn start_server() -> TestServer {
+    let state = test_state(&test_config());
+    actix_test::start(move || test_app(&state))
+}
+
+async fn beckon_one(server: &TestServer) -> String {
+    let mut response = server.post("/cows/beckon").send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    body["cows"][0]["name"].as_str().unwrap().to_string()
+}
+
+async fn open_chat(server: &TestServer, cow: &str) -> Chat {
+    let (_, chat) = awc::Client::new().ws(server.url(&format!("/cows/chat/{}", cow))).connect().await.unwrap();
+    chat
+}
+
+async fn get_json(server: &TestServer, path: &str) -> Value {
+    let mut response = server.get(path).send().await.unwrap();
+    assert!(response.status().is_success(), "GET {} answered {}", path, response.status());
+    response.json().await.unwrap()
+}
+
+// The next text frame, answering pings on the way like a well-behaved client.
+async fn next_text(chat: &mut Chat) -> String {
+    loop {
+        match chat.next().await {
+            Some(Ok(Frame::Text(text))) => return String::from_utf8(text.to_vec()).unwrap(),
+            Some(Ok(Frame::Ping(ping))) => chat.send(Message::Pong(ping)).await.unwrap(),
+            other => panic!("Expected a text frame, got {:?}", other),
+        }
+    }
+}
+
+async fn next_json(chat: &mut Chat) -> Value {
+    serde_json::from_str(&next_text(chat).await).unwrap()
+}
+
+// The transcript writer records sessions in batches, so the end of a chat shows
+// up in the history a little after the socket closes. With auth enabled, the
+// history is read with the given key.
+pub(super) async fn finished_session(server: &TestServer, cow: &str, key: Option<&str>) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut request = server.get(format!("/cows/{}/sessions", cow));
+        if let Some(key) = key {
+            request = request.insert_header(("Authorization", format!("Bearer {}", key)));
+        }
+        let mut response = request.send().await.unwrap();
+        assert!(response.status().is_success(), "The history of {} answered {}", cow, response.status());
+        let body: Value = response.json().await.unwrap();
+        let session = &body["sessions"][0];
+        if !session["ended_at"].is_null() {
+            return session.clone();
+        }
+        assert!(Instant::now() < deadline, "The chat with {} was never recorded as finished: {}", cow, body);
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[actix_web::test]
+async fn plain_text_chat_round_trip() {
+    let server = start_server();
+    let cow = beckon_one(&server).await;
+    let mut chat = open_chat(&server, &cow.to_lowercase()).await;
+
+    chat.send(Message::Text("What a happy, sunny day!".into())).await.unwrap();
+    let reply = next_text(&mut chat).await;
+    assert!(!reply.is_empty());
+
+    let occupants = get_json(&server, &format!("/cows/{}/occupants", cow)).await;
+    assert_eq!(occupants["occupants"].as_array().unwrap().len(), 1);
+    let presence = get_json(&server, "/cows/presence").await;
+    assert_eq!(presence["cows"][0]["cow"], cow.as_str());
+    assert_eq!(presence["cows"][0]["sessions"], 1);
+
+    chat.send(Message::Close(None)).await.unwrap();
+    let session = finished_session(&server, &cow, None).await;
+    assert_eq!(session["close_reason"], "client_closed");
+    assert_eq!(session["message_count"], 2);
+
+    let messages = get_json(&server, &format!("/sessions/{}/messages", session["id"])).await;
+    let messages = messages["messages"].as_array().unwrap();
+    assert_eq!(messages[0]["sender"], "user");
+    assert_eq!(messages[0]["body"], "What a happy, sunny day!");
+    assert_eq!(messages[1]["sender"], "cow");
+    assert_eq!(messages[1]["body"], reply.as_str());
+
+    let stats = get_json(&server, &format!("/cows/{}/stats", cow)).await;
+    assert_eq!(stats["sessions"], 1);
+}
+
+#[actix_web::test]
+async fn json_chat_round_trip() {
+    let server = start_server();
+    let cow = beckon_one(&server).await;
+    let (response, mut chat) = awc::Client::new()
+        .ws(server.url(&format!("/cows/chat/{}", cow)))
+        .protocols([JSON_PROTOCOL])
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(response.headers().get("sec-websocket-protocol").unwrap(), JSON_PROTOCOL);
+
+    let greeting = next_json(&mut chat).await;
+    assert_eq!(greeting["type"], "system");
+    assert!(greeting["body"].as_str().unwrap().starts_with(&format!("You are chatting with {} as guest-", cow)));
+
+    let say = json!({ "type": "say", "id": "c1", "body": "Is the grass greener over there?" });
+    chat.send(Message::Text(say.to_string().into())).await.unwrap();
+    let ack = next_json(&mut chat).await;
+    assert_eq!((&ack["type"], &ack["body"]["ref"]), (&json!("ack"), &json!("c1")));
+    let reply = next_json(&mut chat).await;
+    assert_eq!(reply["type"], "say");
+    assert_eq!(reply["body"]["from"], cow.as_str());
+
+    chat.send(Message::Text("{\"type\": \"shout\", \"id\": \"c2\"}".into())).await.unwrap();
+    let error = next_json(&mut chat).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["body"]["code"], "unknown_type");
+    assert_eq!(error["body"]["ref"], "c2");
+}
+
+#[actix_web::test]
+async fn chats_with_the_same_cow_hear_each_other() {
+    let server = start_server();
+    let cow = beckon_one(&server).await;
+    let mut first = open_chat(&server, &cow).await;
+    let mut second = open_chat(&server, &cow).await;
+
+    let joined = next_text(&mut first).await;
+    assert!(joined.starts_with("* guest-") && joined.ends_with(" joined"), "{}", joined);
+
+    second.send(Message::Text("Hello, everyone".into())).await.unwrap();
+    let reply = next_text(&mut second).await;
+    let heard = next_text(&mut first).await;
+    assert!(heard.starts_with("guest-") && heard.ends_with(": Hello, everyone"), "{}", heard);
+    assert_eq!(next_text(&mut first).await, reply);
+}
+
+#[actix_web::test]
+async fn chat_with_a_missing_cow_is_refused() {
+    let server = start_server();
+    let response = server.get("/cows/chat/Nobody").send().await.unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+// The test config pings every second and gives up after two seconds of silence.
+// This client never answers, so the server has to hang up on it.
+#[actix_web::test]
+async fn silent_client_is_disconnected_after_the_heartbeat_timeout() {
+    let server = start_server();
+    let cow = beckon_one(&server).await;
+    // Timed from before the handshake, since the server's clock starts during it.
+    let started = Instant::now();
+    let mut chat = open_chat(&server, &cow).await;
+
+    let mut pings = 0;
+    let hung_up = actix_web::rt::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match chat.next().await {
+                Some(Ok(Frame::Ping(_))) => pings += 1,
+                Some(Ok(Frame::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(other)) => panic!("Unexpected frame {:?}", other),
+            }
+        }
+    }).await;
+    assert!(hung_up.is_ok(), "The server never hung up");
+    assert!(pings >= 1, "The server hung up without pinging first");
+    assert!(started.elapsed() >= Duration::from_secs(2));
+
+    let session = finished_session(&server, &cow, None).await;
+    assert_eq!(session["close_reason"], "heartbeat_timeout");
+    assert_eq!(session["message_count"], 0);
+}
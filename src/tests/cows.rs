@@ -0,0 +1,353 @@
+use std::time::Duration;
+
+use actix_web::{
+    body::MessageBody,
+    test::{self, TestRequest},
+};
+use r2d2::Pool;
+use r2d2_sqlite::{SqliteConnectionManager, rusqlite};
+use serde_json::{Value, json};
+
+use crate::metrics::Metrics;
+use crate::random::SEED_HEADER;
+use crate::tests::{call, memory_pool, migrated, test_app, test_config, test_state, test_state_with};
+
+fn beckon(count: u32) -> TestRequest {
+    TestRequest::post().uri("/cows/beckon").set_json(json!({ "count": count }))
+}
+
+// The names of the cows in a CowListResponse or CowPageResponse.
+fn names(body: &Value) -> Vec<String> {
+    body["cows"].as_array().unwrap().iter().map(|cow| cow["name"].as_str().unwrap().to_string()).collect()
+}
+
+#[actix_web::test]
+async fn count_starts_at_zero_and_follows_beckoning() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (status, body) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!((status, body), (200, json!(0)));
+
+    let (status, body) = call(&app, beckon(3).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(names(&body).len(), 3);
+    let (_, body) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(body, json!(3));
+}
+
+#[actix_web::test]
+async fn beckoned_cows_have_every_attribute() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(5).to_request()).await;
+    for cow in body["cows"].as_array().unwrap() {
+        assert!(cow["name"].is_string());
+        assert!(cow["id"].is_u64());
+        assert!(cow["color"].is_string());
+        assert!((5..=30).contains(&cow["age"].as_u64().unwrap()));
+        assert!((1300..=1800).contains(&cow["weight"].as_u64().unwrap()));
+        assert!(["cheerful", "grumpy", "philosophical"].contains(&cow["personality"].as_str().unwrap()));
+    }
+}
+
+#[actix_web::test]
+async fn beckon_count_must_be_between_one_and_five() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    for count in [0, 6] {
+        let (status, body) = call(&app, beckon(count).to_request()).await;
+        assert_eq!(status, 422, "count {}", count);
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(body["detail"], "count: range");
+    }
+    for count in [1, 5] {
+        let (status, _) = call(&app, beckon(count).to_request()).await;
+        assert_eq!(status, 200, "count {}", count);
+    }
+}
+
+#[actix_web::test]
+async fn beckon_needs_a_numeric_count() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    for body in [json!({}), json!({ "count": "three" }), json!({ "count": -1 })] {
+        let request = TestRequest::post().uri("/cows/beckon").set_json(&body).to_request();
+        let (status, problem) = call(&app, request).await;
+        assert_eq!(status, 400, "body {}", body);
+        assert_eq!(problem["code"], "bad_request");
+    }
+}
+
+// The built-in catalog has 44 names, and each cow in the meadow needs its own.
+#[actix_web::test]
+async fn a_full_meadow_refuses_more_cows() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    for _ in 0..8 {
+        let (status, _) = call(&app, beckon(5).to_request()).await;
+        assert_eq!(status, 200);
+    }
+    // Only four names are left, so only four cows come.
+    let (status, body) = call(&app, beckon(5).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(names(&body).len(), 4);
+
+    let (status, body) = call(&app, beckon(1).to_request()).await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "meadow_full");
+    assert_eq!(body["type"], "about:blank");
+    assert_eq!(body["status"], 409);
+
+    // Making room lets a cow in again.
+    let (status, _) = call(&app, TestRequest::post().uri("/cows/release").set_json(json!({ "count": 1 })).to_request()).await;
+    assert_eq!(status, 200);
+    let (status, body) = call(&app, beckon(1).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(names(&body).len(), 1);
+}
+
+#[actix_web::test]
+async fn list_filters_sorts_and_pages() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    call(&app, beckon(5).to_request()).await;
+    call(&app, beckon(5).to_request()).await;
+
+    let (status, body) = call(&app, TestRequest::get().uri("/cows/list").to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["total"], 10);
+    let ids: Vec<u64> = body["cows"].as_array().unwrap().iter().map(|cow| cow["id"].as_u64().unwrap()).collect();
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "sorted by id by default: {:?}", ids);
+
+    let (_, body) = call(&app, TestRequest::get().uri("/cows/list?sort=age&order=desc&limit=3&offset=2").to_request()).await;
+    assert_eq!(body["total"], 10);
+    assert_eq!(body["limit"], 3);
+    assert_eq!(body["offset"], 2);
+    let ages: Vec<u64> = body["cows"].as_array().unwrap().iter().map(|cow| cow["age"].as_u64().unwrap()).collect();
+    assert_eq!(ages.len(), 3);
+    assert!(ages.windows(2).all(|pair| pair[0] >= pair[1]), "sorted by age, oldest first: {:?}", ages);
+
+    let (_, body) = call(&app, TestRequest::get().uri("/cows/list?min_age=10&max_age=20").to_request()).await;
+    for cow in body["cows"].as_array().unwrap() {
+        assert!((10..=20).contains(&cow["age"].as_u64().unwrap()));
+    }
+}
+
+#[actix_web::test]
+async fn list_rejects_invalid_filters() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    for query in ["limit=0", "limit=101", "min_age=20&max_age=10", "color=purple"] {
+        let (status, body) = call(&app, TestRequest::get().uri(&format!("/cows/list?{}", query)).to_request()).await;
+        assert_eq!(status, 422, "query {}", query);
+        assert_eq!(body["code"], "validation_failed");
+    }
+    let (status, _) = call(&app, TestRequest::get().uri("/cows/list?sort=mood").to_request()).await;
+    assert_eq!(status, 400);
+}
+
+#[actix_web::test]
+async fn get_cow_by_name_and_by_id() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(1).to_request()).await;
+    let cow = &body["cows"][0];
+    let name = cow["name"].as_str().unwrap();
+
+    // Names are capitalized before they are looked up, and trailing slashes don't matter.
+    let uri = format!("/cows/{}/", name.to_lowercase());
+    let (status, by_name) = call(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(&by_name, cow);
+
+    let (status, by_id) = call(&app, TestRequest::get().uri(&format!("/cows/id/{}", cow["id"])).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(&by_id, cow);
+
+    let (status, body) = call(&app, TestRequest::get().uri("/cows/Nobody").to_request()).await;
+    assert_eq!(status, 404);
+    assert_eq!(body["code"], "not_found");
+    let (status, _) = call(&app, TestRequest::get().uri("/cows/id/9999").to_request()).await;
+    assert_eq!(status, 404);
+    // An id that isn't a number is answered like the others, not by actix-web.
+    let response = test::call_service(&app, TestRequest::get().uri("/cows/id/Bessie").to_request()).await;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/problem+json");
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "not_found");
+}
+
+// What SQLite said stays in the server's log.
+#[actix_web::test]
+async fn database_errors_dont_tell_clients_the_details() {
+    let pool = migrated(memory_pool());
+    let app = test::init_service(test_app(&test_state_with(&test_config(), pool.clone(), Metrics::new()))).await;
+    pool.get().unwrap().execute("DROP TABLE cows", []).unwrap();
+
+    let (status, body) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(status, 500);
+    assert_eq!(body["code"], "database_error");
+    assert_eq!(body["detail"], "Something went wrong on our side");
+}
+
+#[actix_web::test]
+async fn update_changes_only_what_is_given() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(1).to_request()).await;
+    let cow = body["cows"][0].clone();
+    let uri = format!("/cows/{}", cow["name"].as_str().unwrap());
+
+    let update = json!({ "color": "tan", "age": 7 });
+    let (status, updated) = call(&app, TestRequest::patch().uri(&uri).set_json(update).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(updated["color"], "Tan");
+    assert_eq!(updated["age"], 7);
+    assert_eq!(updated["weight"], cow["weight"]);
+    assert_eq!(updated["personality"], cow["personality"]);
+
+    let (status, body) = call(&app, TestRequest::patch().uri(&uri).set_json(json!({ "weight": 9000 })).to_request()).await;
+    assert_eq!(status, 422);
+    assert_eq!(body["detail"], "weight: range");
+    let (status, _) = call(&app, TestRequest::patch().uri(&uri).set_json(json!({})).to_request()).await;
+    assert_eq!(status, 422);
+    let (status, _) = call(&app, TestRequest::patch().uri("/cows/Nobody").set_json(json!({ "age": 7 })).to_request()).await;
+    assert_eq!(status, 404);
+}
+
+#[actix_web::test]
+async fn release_one_cow_by_name() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(2).to_request()).await;
+    let name = names(&body).remove(0);
+    let uri = format!("/cows/{}", name);
+
+    let (status, body) = call(&app, TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(names(&body), vec![name]);
+    let (status, _) = call(&app, TestRequest::delete().uri(&uri).to_request()).await;
+    assert_eq!(status, 404);
+    let (_, body) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(body, json!(1));
+}
+
+#[actix_web::test]
+async fn release_by_count_or_by_names() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(5).to_request()).await;
+    let beckoned = names(&body);
+
+    let request = TestRequest::post().uri("/cows/release").set_json(json!({ "count": 2 })).to_request();
+    let (status, body) = call(&app, request).await;
+    assert_eq!(status, 200);
+    let released = names(&body);
+    assert_eq!(released.len(), 2);
+
+    let remaining: Vec<&String> = beckoned.iter().filter(|name| !released.contains(name)).collect();
+    let lowercase: Vec<String> = remaining.iter().map(|name| name.to_lowercase()).collect();
+    let request = TestRequest::post().uri("/cows/release").set_json(json!({ "names": lowercase })).to_request();
+    let (status, body) = call(&app, request).await;
+    assert_eq!(status, 200);
+    assert_eq!(names(&body).len(), 3);
+    let (_, body) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(body, json!(0));
+
+    // Exactly one of count and names, and no blank names.
+    let invalids = [
+        json!({}), json!({ "count": 1, "names": ["Bessie"] }), json!({ "count": 0 }),
+        json!({ "names": [""] }), json!({ "names": ["Bessie", "  "] }),
+    ];
+    for invalid in invalids {
+        let request = TestRequest::post().uri("/cows/release").set_json(&invalid).to_request();
+        let (status, _) = call(&app, request).await;
+        assert_eq!(status, 422, "body {}", invalid);
+    }
+}
+
+#[actix_web::test]
+async fn a_cow_nobody_chatted_with_has_no_history() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (_, body) = call(&app, beckon(1).to_request()).await;
+    let name = names(&body).remove(0);
+
+    let (status, body) = call(&app, TestRequest::get().uri(&format!("/cows/{}/sessions", name)).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["sessions"], json!([]));
+    assert_eq!(body["total"], 0);
+
+    let (status, body) = call(&app, TestRequest::get().uri(&format!("/cows/{}/stats", name)).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["sessions"], 0);
+    assert_eq!(body["average_duration_secs"], 0.0);
+
+    let (status, body) = call(&app, TestRequest::get().uri(&format!("/cows/{}/occupants", name)).to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["occupants"], json!([]));
+
+    let (status, _) = call(&app, TestRequest::get().uri(&format!("/cows/{}/sessions?limit=0", name)).to_request()).await;
+    assert_eq!(status, 422);
+    for route in ["sessions", "stats", "occupants"] {
+        let (status, _) = call(&app, TestRequest::get().uri(&format!("/cows/Nobody/{}", route)).to_request()).await;
+        assert_eq!(status, 404, "route {}", route);
+    }
+    let (status, _) = call(&app, TestRequest::get().uri("/sessions/1/messages").to_request()).await;
+    assert_eq!(status, 404);
+}
+
+#[actix_web::test]
+async fn presence_is_empty_without_chats() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (status, body) = call(&app, TestRequest::get().uri("/cows/presence").to_request()).await;
+    assert_eq!((status, body), (200, json!({ "cows": [] })));
+}
+
+// The stream never ends, so we only read the snapshot it starts with.
+#[actix_web::test]
+async fn presence_stream_starts_with_a_snapshot() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let response = test::call_service(&app, TestRequest::get().uri("/cows/presence/stream").to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/event-stream");
+
+    let mut body = response.into_body();
+    let chunk = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_next(cx)).await;
+    let Some(Ok(chunk)) = chunk else { panic!("The stream ended without a snapshot") };
+    assert_eq!(std::str::from_utf8(&chunk).unwrap(), "event: snapshot\ndata: {\"cows\":[]}\n\n");
+}
+
+#[actix_web::test]
+async fn the_same_seed_beckons_the_same_cows() {
+    let first = test::init_service(test_app(&test_state(&test_config()))).await;
+    let second = test::init_service(test_app(&test_state(&test_config()))).await;
+    let seeded = || beckon(5).insert_header((SEED_HEADER.clone(), "7")).to_request();
+
+    let (_, from_first) = call(&first, seeded()).await;
+    let (_, from_second) = call(&second, seeded()).await;
+    assert_eq!(from_first, from_second);
+
+    let request = beckon(1).insert_header((SEED_HEADER.clone(), "moo")).to_request();
+    let (status, body) = call(&first, request).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "bad_request");
+}
+
+#[actix_web::test]
+async fn the_built_in_catalog_cant_be_reloaded() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (status, body) = call(&app, TestRequest::post().uri("/admin/catalog/reload").to_request()).await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "conflict");
+}
+
+// Another connection holds the write lock for a moment. SQLite gives up on it
+// right away here, so beckoning only gets through by trying again.
+#[actix_web::test]
+async fn beckoning_tries_again_while_the_meadow_is_busy() {
+    let path = std::env::temp_dir().join(format!("cowchat-busy-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let manager = SqliteConnectionManager::file(&path).with_init(|conn| conn.busy_timeout(Duration::ZERO));
+    let pool = migrated(Pool::builder().max_size(1).build(manager).unwrap());
+    let app = test::init_service(test_app(&test_state_with(&test_config(), pool, Metrics::new()))).await;
+
+    let blocker = rusqlite::Connection::open(&path).unwrap();
+    blocker.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let unblock = async {
+        actix_web::rt::time::sleep(Duration::from_millis(30)).await;
+        blocker.execute_batch("COMMIT").unwrap();
+    };
+    let ((status, body), ()) = futures_util::join!(call(&app, beckon(2).to_request()), unblock);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(names(&body).len(), 2);
+    let _ = std::fs::remove_file(&path);
+}
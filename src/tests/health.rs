@@ -0,0 +1,66 @@
+use actix_web::test::{self, TestRequest};
+use serde_json::json;
+
+use crate::config::Config;
+use crate::db::migrations::latest_schema_version;
+use crate::metrics::Metrics;
+use crate::tests::{call, memory_pool, migrated, test_app, test_config, test_state, test_state_with};
+
+fn quick_timeout() -> Config {
+    let mut config = test_config();
+    config.health.readiness_timeout_ms = 200;
+    config
+}
+
+#[actix_web::test]
+async fn healthz_always_answers() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (status, body) = call(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!((status, body), (200, json!({ "status": "ok" })));
+}
+
+#[actix_web::test]
+async fn readyz_reports_the_pool_and_schema() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let (status, body) = call(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["database"]["ok"], true);
+    assert!(body["database"]["error"].is_null());
+    assert_eq!(body["pool"], json!({ "connections": 1, "idle": 1, "max_size": 1 }));
+    assert_eq!(body["schema_version"], latest_schema_version());
+    assert_eq!(body["expected_schema_version"], latest_schema_version());
+}
+
+#[actix_web::test]
+async fn readyz_fails_when_no_connection_is_free() {
+    let config = quick_timeout();
+    // The test keeps a handle on the pool.
+    let pool = migrated(memory_pool());
+    let app = test::init_service(test_app(&test_state_with(&config, pool.clone(), Metrics::new()))).await;
+
+    // The pool has a single connection, and the test is holding on to it.
+    let held = pool.get().unwrap();
+    let (status, body) = call(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(status, 503);
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["database"]["ok"], false);
+    assert!(body["database"]["error"].is_string());
+    assert!(body["schema_version"].is_null());
+    assert_eq!(body["pool"]["idle"], 0);
+
+    drop(held);
+    let (status, _) = call(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn readyz_fails_on_an_outdated_schema() {
+    let config = quick_timeout();
+    let app = test::init_service(test_app(&test_state_with(&config, memory_pool(), Metrics::new()))).await;
+
+    let (status, body) = call(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(status, 503);
+    assert_eq!(body["database"]["ok"], true);
+    assert_eq!(body["schema_version"], 0);
+}
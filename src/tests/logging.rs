@@ -0,0 +1,62 @@
+use actix_web::{
+    dev::ServiceResponse,
+    test::{self, TestRequest},
+    web::get,
+};
+use serde_json::json;
+
+use crate::CowchatServer;
+use crate::app::build_app;
+use crate::logging::{REQUEST_ID_HEADER, redacted_uri, request_id};
+use crate::tests::{call, test_app, test_config, test_state};
+
+fn id_of<B>(response: &ServiceResponse<B>) -> String {
+    response.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn requests_without_an_id_get_a_new_one() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let first = id_of(&test::call_service(&app, TestRequest::get().uri("/cows/count").to_request()).await);
+    let second = id_of(&test::call_service(&app, TestRequest::get().uri("/cows/count").to_request()).await);
+    assert_eq!(first.len(), 16);
+    assert!(first.chars().all(|c| c.is_ascii_hexdigit()), "{}", first);
+    assert_ne!(first, second);
+}
+
+#[actix_web::test]
+async fn plain_request_ids_are_passed_on() {
+    let app = test::init_service(test_app(&test_state(&test_config()))).await;
+    let request = TestRequest::get().uri("/cows/Nobody").insert_header((REQUEST_ID_HEADER, "lb-1234_abc.9"));
+    let response = test::call_service(&app, request.to_request()).await;
+    assert_eq!(response.status(), 404);
+    assert_eq!(id_of(&response), "lb-1234_abc.9");
+
+    for unwelcome in ["two words", "<script>", &"x".repeat(129)] {
+        let request = TestRequest::get().uri("/cows/count").insert_header((REQUEST_ID_HEADER, unwelcome));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(id_of(&response).len(), 16, "{:?} was passed on", unwelcome);
+    }
+}
+
+// Anything a handler (or something it calls) logs can see the ID.
+#[actix_web::test]
+async fn handlers_see_the_request_id() {
+    let server = CowchatServer::new(test_config()).routes(|cfg| {
+        cfg.route("/whoami", get().to(|| async { serde_json::to_string(&request_id()).unwrap() }));
+    });
+    let app = test::init_service(build_app(&test_state(&test_config()), &server.extras)).await;
+    let request = TestRequest::get().uri("/whoami").insert_header((REQUEST_ID_HEADER, "abc-123"));
+    assert_eq!(call(&app, request.to_request()).await, (200, json!("abc-123")));
+    assert_eq!(request_id(), None);
+}
+
+#[test]
+fn keys_in_the_query_are_left_out_of_the_log() {
+    let uri = |query: &str| redacted_uri(&TestRequest::get().uri(&format!("/cows/chat/Bessie?{}", query)).to_srv_request());
+    assert_eq!(uri("access_token=cowchat_1&x=1"), "/cows/chat/Bessie?access_token=REDACTED&x=1");
+    // Encoded names are the same parameter to the auth middleware.
+    assert_eq!(uri("x=1&access%5Ftoken=cowchat_1"), "/cows/chat/Bessie?x=1&access_token=REDACTED");
+    assert_eq!(uri("access%5ftoken=cowchat_1"), "/cows/chat/Bessie?access_token=REDACTED");
+    assert_eq!(uri("token=1"), "/cows/chat/Bessie?token=1");
+}
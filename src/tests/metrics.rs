@@ -0,0 +1,72 @@
+use actix_web::{
+    body::MessageBody,
+    dev::ServiceResponse,
+    test::{self, TestRequest},
+};
+use r2d2::Pool;
+use serde_json::json;
+
+use crate::app::AppState;
+use crate::metrics::Metrics;
+use crate::tests::{call, memory_pool_with, migrated, test_app, test_config, test_state_with};
+
+// Like test_state(), but with the pool reporting its waits, as the server's own
+// pool does.
+fn measured_state() -> AppState {
+    let metrics = Metrics::new();
+    let pool = migrated(memory_pool_with(Pool::builder().event_handler(metrics.pool_events())));
+    test_state_with(&test_config(), pool, metrics)
+}
+
+fn scrape() -> TestRequest {
+    TestRequest::get().uri("/metrics")
+}
+
+// The lines of a /metrics response that aren't # HELP or # TYPE comments.
+async fn samples<B: MessageBody>(response: ServiceResponse<B>) -> Vec<String> {
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), prometheus::TEXT_FORMAT);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    body.lines().filter(|line| !line.starts_with('#')).map(str::to_string).collect()
+}
+
+fn has(lines: &[String], line: &str) -> bool {
+    lines.iter().any(|l| l == line)
+}
+
+#[actix_web::test]
+async fn metrics_count_requests_per_route() {
+    let app = test::init_service(test_app(&measured_state())).await;
+    let (_, body) = call(&app, TestRequest::post().uri("/cows/beckon").set_json(json!({ "count": 2 })).to_request()).await;
+    for cow in body["cows"].as_array().unwrap() {
+        let uri = format!("/cows/{}", cow["name"].as_str().unwrap());
+        call(&app, TestRequest::get().uri(&uri).to_request()).await;
+    }
+    call(&app, TestRequest::get().uri("/cows/Nobody").to_request()).await;
+
+    let lines = samples(test::call_service(&app, scrape().to_request()).await).await;
+    // Both cows count for the one route, rather than a path each.
+    assert!(has(&lines, r#"cowchat_http_requests_total{method="GET",route="/cows/{cow_name}",status="200"} 2"#), "{:#?}", lines);
+    assert!(has(&lines, r#"cowchat_http_requests_total{method="GET",route="/cows/{cow_name}",status="404"} 1"#));
+    assert!(has(&lines, r#"cowchat_http_requests_total{method="POST",route="/cows/beckon",status="200"} 1"#));
+    assert!(has(&lines, r#"cowchat_http_request_duration_seconds_count{method="GET",route="/cows/{cow_name}"} 3"#));
+    assert!(has(&lines, "cowchat_herd_size 2"));
+    assert!(has(&lines, "cowchat_chat_sessions_open 0"));
+    assert!(has(&lines, "cowchat_db_pool_connections 1"));
+    assert!(lines.iter().any(|l| l.starts_with("cowchat_db_pool_wait_seconds_count ")));
+    // /metrics itself is outside the /cows scope.
+    assert!(!lines.iter().any(|l| l.contains(r#"route="/metrics""#)));
+}
+
+// The built-in catalog has 44 names, so the ninth beckon of five finds the meadow full.
+#[actix_web::test]
+async fn metrics_count_beckon_outcomes() {
+    let app = test::init_service(test_app(&measured_state())).await;
+    for _ in 0..10 {
+        call(&app, TestRequest::post().uri("/cows/beckon").set_json(json!({ "count": 5 })).to_request()).await;
+    }
+    let lines = samples(test::call_service(&app, scrape().to_request()).await).await;
+    assert!(has(&lines, r#"cowchat_beckon_total{outcome="success"} 9"#), "{:#?}", lines);
+    assert!(has(&lines, r#"cowchat_beckon_total{outcome="meadow_full"} 1"#));
+    assert!(has(&lines, "cowchat_herd_size 44"));
+}
@@ -0,0 +1,102 @@
+// Integration tests. They build the same app the server runs, minus the server,
+// on top of a fresh in-memory database for every test.
+mod auth;
+mod chat;
+mod cows;
+mod health;
+mod logging;
+mod metrics;
+mod ratelimit;
+mod server;
+mod shutdown;
+mod tls;
+mod transcripts;
+
+use actix_web::{
+    App, Error,
+    body::MessageBody,
+    dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    test,
+};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::Value;
+
+use crate::app::{AppState, Extras, build_app};
+use crate::catalog::CatalogStore;
+use crate::config::{ChatConfig, Config, RandomConfig};
+use crate::db::migrations::migrate_up;
+use crate::db::types::MyPool;
+use crate::metrics::Metrics;
+
+// Short chat timeouts, so that the heartbeat test doesn't take all day, and a
+// fixed seed with test mode, so that tests can ask for the cows they want.
+pub(crate) fn test_config() -> Config {
+    Config {
+        chat: ChatConfig { client_timeout_secs: 2, heartbeat_interval_secs: 1 },
+        random: RandomConfig { seed: Some(42), test_mode: true },
+        ..Config::default()
+    }
+}
+
+// Every in-memory SQLite connection is a database of its own, so the pool gets
+// exactly one connection and never lets go of it.
+pub(crate) fn memory_pool() -> MyPool {
+    memory_pool_with(Pool::builder())
+}
+
+// The same, from a builder that may have more settings, like an event handler.
+pub(crate) fn memory_pool_with(builder: r2d2::Builder<SqliteConnectionManager>) -> MyPool {
+    builder.max_size(1)
+           .idle_timeout(None)
+           .max_lifetime(None)
+           .build(SqliteConnectionManager::memory())
+           .unwrap()
+}
+
+// Brings the pool's database up to date, and hands the pool back.
+pub(crate) fn migrated(pool: MyPool) -> MyPool {
+    migrate_up(&mut pool.get().unwrap()).unwrap();
+    pool
+}
+
+// Binding to port 0 picks a free port, which is then given back for a server.
+pub(crate) fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub(crate) fn test_state(config: &Config) -> AppState {
+    test_state_with(config, migrated(memory_pool()), Metrics::new())
+}
+
+// A state on a pool of the test's own, which is used as it is, migrated or not.
+pub(crate) fn test_state_with(config: &Config, pool: MyPool, metrics: Metrics) -> AppState {
+    let catalog = CatalogStore::load(&config.catalog).unwrap();
+    AppState::new(pool, catalog, config, metrics)
+}
+
+// The app as the server builds it, with nothing added.
+pub(crate) fn test_app(state: &AppState) -> App<impl ServiceFactory<
+    ServiceRequest,
+    Config = (),
+    Response = ServiceResponse<impl MessageBody>,
+    Error = Error,
+    InitError = (),
+>> {
+    build_app(state, &Extras::default())
+}
+
+// Sends a request to an app from test::init_service() and returns the status
+// and the JSON body. Error bodies are JSON too, and so is a bare count.
+pub(crate) async fn call<S, R, B>(app: &S, request: R) -> (u16, Value)
+where
+    S: Service<R, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, request).await;
+    let status = response.status().as_u16();
+    let body = test::read_body(response).await;
+    let json = serde_json::from_slice(&body)
+        .unwrap_or_else(|e| panic!("Response is not JSON ({}): {:?}", e, body));
+    (status, json)
+}
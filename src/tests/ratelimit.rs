@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use actix_web::test::{self, TestRequest};
+use actix_web_actors::ws::CloseCode;
+use awc::ws::{Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+
+use crate::auth::create_user;
+use crate::config::{AuthConfig, Config, RateLimitConfig};
+use crate::metrics::Metrics;
+use crate::ratelimit::{Budget, TokenBucket};
+use crate::tests::{call, memory_pool, migrated, test_app, test_config, test_state, test_state_with};
+
+fn limited_config(rate_limit: RateLimitConfig) -> Config {
+    Config { rate_limit: RateLimitConfig { enabled: true, ..rate_limit }, ..test_config() }
+}
+
+#[test]
+fn buckets_refill_at_their_rate_up_to_the_burst() {
+    let budget = Budget { burst: 2, per_minute: 60 };
+    let start = Instant::now();
+    let mut bucket = TokenBucket::full(budget, start);
+    assert_eq!(bucket.take(budget, start), Ok(()));
+    assert_eq!(bucket.take(budget, start), Ok(()));
+    assert_eq!(bucket.take(budget, start), Err(Duration::from_secs(1)));
+    // Half a token is not a token.
+    let later = start + Duration::from_millis(500);
+    assert_eq!(bucket.take(budget, later), Err(Duration::from_millis(500)));
+    assert_eq!(bucket.take(budget, start + Duration::from_secs(1)), Ok(()));
+
+    // A long wait doesn't save up more than the burst.
+    let much_later = start + Duration::from_secs(60);
+    for _ in 0..2 {
+        assert_eq!(bucket.take(budget, much_later), Ok(()));
+    }
+    assert!(bucket.take(budget, much_later).is_err());
+}
+
+#[actix_web::test]
+async fn clients_over_their_budget_get_a_429_with_retry_after() {
+    let config = limited_config(RateLimitConfig { request_burst: 2, requests_per_minute: 1, ..Default::default() });
+    let state = test_state(&config);
+    let app = test::init_service(test_app(&state)).await;
+    let from = |ip: &str| TestRequest::get().uri("/cows/count").peer_addr(format!("{}:4000", ip).parse().unwrap());
+
+    for _ in 0..2 {
+        assert_eq!(call(&app, from("10.0.0.1").to_request()).await.0, 200);
+    }
+    let response = test::call_service(&app, from("10.0.0.1").to_request()).await;
+    assert_eq!(response.status(), 429);
+    // A token a minute, and the one taken first is only a few milliseconds old.
+    assert_eq!(response.headers().get("retry-after").unwrap(), "60");
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(problem["code"], "rate_limited");
+
+    // Other scopes share the budget, other clients have their own, and the probes have none.
+    let messages = TestRequest::get().uri("/sessions/1/messages").peer_addr("10.0.0.1:4000".parse().unwrap());
+    assert_eq!(call(&app, messages.to_request()).await.0, 429);
+    assert_eq!(call(&app, from("10.0.0.2").to_request()).await.0, 200);
+    let healthz = TestRequest::get().uri("/healthz").peer_addr("10.0.0.1:4000".parse().unwrap());
+    assert_eq!(call(&app, healthz.to_request()).await.0, 200);
+
+    let metrics = test::call_and_read_body(&app, TestRequest::get().uri("/metrics").to_request()).await;
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    assert!(metrics.contains("cowchat_rate_limited_total{kind=\"request\"} 2"), "{}", metrics);
+}
+
+// Requests without a valid key never reach the per-user limits, so they are
+// counted against their IP address instead.
+#[actix_web::test]
+async fn guessing_keys_is_rate_limited_too() {
+    let config = Config {
+        auth: AuthConfig { enabled: true },
+        ..limited_config(RateLimitConfig { request_burst: 3, requests_per_minute: 1, ..Default::default() })
+    };
+    let pool = migrated(memory_pool());
+    let key = create_user(&mut pool.get().unwrap(), "visitor", false).unwrap();
+    let state = test_state_with(&config, pool, Metrics::new());
+    let app = test::init_service(test_app(&state)).await;
+    let request = |ip: &str, key: &str| TestRequest::get().uri("/cows/count")
+        .peer_addr(format!("{}:4000", ip).parse().unwrap())
+        .insert_header(("Authorization", format!("Bearer {}", key)));
+
+    for guess in 0..3 {
+        assert_eq!(call(&app, request("10.0.0.1", &format!("cowchat_{}", guess)).to_request()).await.0, 401);
+    }
+    let (status, problem) = call(&app, request("10.0.0.1", "cowchat_3").to_request()).await;
+    assert_eq!((status, &problem["code"]), (429, &json!("rate_limited")));
+    // Once the address is out of tokens, even a good key has to wait, but other addresses don't.
+    assert_eq!(call(&app, request("10.0.0.1", &key).to_request()).await.0, 429);
+    assert_eq!(call(&app, request("10.0.0.2", &key).to_request()).await.0, 200);
+}
+
+#[actix_web::test]
+async fn chats_that_keep_flooding_are_warned_and_then_closed() {
+    let config = limited_config(RateLimitConfig {
+        chat_message_burst: 2,
+        chat_messages_per_minute: 1,
+        chat_throttle_warnings: 2,
+        ..Default::default()
+    });
+    let state = test_state(&config);
+    let server = actix_test::start(move || test_app(&state));
+    let mut response = server.post("/cows/beckon").send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+    let (_, mut chat) = awc::Client::new().ws(server.url(&format!("/cows/chat/{}", cow))).connect().await.unwrap();
+
+    let mut texts = Vec::new();
+    for n in 0..5 {
+        chat.send(Message::Text(format!("Moo {}", n).into())).await.unwrap();
+    }
+    let close = loop {
+        match chat.next().await {
+            Some(Ok(Frame::Text(text))) => texts.push(String::from_utf8(text.to_vec()).unwrap()),
+            Some(Ok(Frame::Ping(_))) => {},
+            Some(Ok(Frame::Close(reason))) => break reason.unwrap(),
+            other => panic!("Expected text or a close frame, got {:?}", other),
+        }
+    };
+    // Two replies, two warnings, and the fifth message ends the chat.
+    assert_eq!(texts.len(), 4, "{:?}", texts);
+    assert!(!texts[1].starts_with("* Slow down"));
+    assert!(texts[2].starts_with("* Slow down") && texts[3].starts_with("* Slow down"), "{:?}", texts);
+    assert_eq!(close.code, CloseCode::Policy);
+}
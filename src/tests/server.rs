@@ -0,0 +1,125 @@
+use actix_web::{
+    HttpMessage,
+    http::header::{HeaderName, HeaderValue},
+    test::{self, TestRequest},
+    web::{get, Data},
+};
+use serde_json::json;
+
+use crate::CowchatServer;
+use crate::app::build_app;
+use crate::tests::{call, free_port, memory_pool, test_config, test_state};
+
+static ORDER_HEADER: HeaderName = HeaderName::from_static("x-hook-order");
+
+// Appends `name` to a header on the way out, to show the order hooks run in.
+fn tag_response(server: CowchatServer, name: &'static str) -> CowchatServer {
+    server.middleware(move |req, next| async move {
+        let mut response = next.call(req).await?;
+        let order = match response.headers().get(&ORDER_HEADER) {
+            Some(order) => format!("{},{}", order.to_str().unwrap(), name),
+            None => name.to_string(),
+        };
+        response.headers_mut().insert(ORDER_HEADER.clone(), HeaderValue::from_str(&order).unwrap());
+        Ok(response)
+    })
+}
+
+#[actix_web::test]
+async fn extra_routes_are_served_next_to_the_built_in_ones() {
+    let server = CowchatServer::new(test_config())
+        .routes(|cfg| {
+            cfg.route("/hello", get().to(|| async { "\"Moo!\"" }));
+        })
+        .routes(|cfg| {
+            cfg.app_data(Data::new(7u32))
+               .route("/number", get().to(|number: Data<u32>| async move { number.to_string() }));
+        });
+    let app = test::init_service(build_app(&test_state(&test_config()), &server.extras)).await;
+
+    assert_eq!(call(&app, TestRequest::get().uri("/hello").to_request()).await, (200, json!("Moo!")));
+    assert_eq!(call(&app, TestRequest::get().uri("/number").to_request()).await, (200, json!(7)));
+    assert_eq!(call(&app, TestRequest::get().uri("/cows/count").to_request()).await, (200, json!(0)));
+}
+
+#[actix_web::test]
+async fn middleware_hooks_run_in_the_order_they_were_added() {
+    let server = tag_response(tag_response(CowchatServer::new(test_config()), "first"), "second");
+    let app = test::init_service(build_app(&test_state(&test_config()), &server.extras)).await;
+
+    let response = test::call_service(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(response.status(), 200);
+    // The first hook is the outermost one, so it sees the response last.
+    assert_eq!(response.headers().get(&ORDER_HEADER).unwrap(), "second,first");
+}
+
+#[actix_web::test]
+async fn middleware_hooks_can_answer_by_themselves() {
+    let server = CowchatServer::new(test_config())
+        .middleware(|req, next| async move {
+            if req.path().starts_with("/admin") {
+                let response = actix_web::HttpResponse::Forbidden().finish();
+                return Ok(req.into_response(response));
+            }
+            req.extensions_mut().insert(42u32);
+            next.call(req).await
+        });
+    let app = test::init_service(build_app(&test_state(&test_config()), &server.extras)).await;
+
+    let response = test::call_service(&app, TestRequest::post().uri("/admin/catalog/reload").to_request()).await;
+    assert_eq!(response.status(), 403);
+    let (status, _) = call(&app, TestRequest::get().uri("/cows/count").to_request()).await;
+    assert_eq!(status, 200);
+}
+
+// The full server, on a port of its own, with a pool handed to it.
+#[actix_web::test]
+async fn started_server_migrates_and_serves_the_given_pool() {
+    let port = free_port();
+    let mut config = test_config();
+    config.server.host = "127.0.0.1".to_string();
+    config.server.port = port;
+    config.server.workers = 1;
+
+    let server = CowchatServer::new(config).pool(memory_pool()).start().unwrap();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let client = awc::Client::new();
+    let mut response = client.post(format!("http://127.0.0.1:{}/cows/beckon", port))
+        .send_json(&json!({ "count": 2 })).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let beckoned: crate::CowListResponse = response.json().await.unwrap();
+    assert_eq!(beckoned.cows.len(), 2);
+
+    let mut response = client.get(format!("http://127.0.0.1:{}/cows/count", port)).send().await.unwrap();
+    assert_eq!(response.body().await.unwrap(), "2");
+    // A graceful stop would wait for the client's keep-alive connection.
+    handle.stop(false).await;
+}
+
+// The server opens its own pool here, which keeps a connection per worker.
+#[actix_web::test]
+async fn more_workers_than_the_default_pool_size_are_fine() {
+    let path = std::env::temp_dir().join(format!("cowchat-workers-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = test_config();
+    config.server.host = "127.0.0.1".to_string();
+    config.server.port = free_port();
+    config.server.workers = 11;
+    config.database.path = path.to_string_lossy().into_owned();
+
+    let server = CowchatServer::new(config).start().unwrap();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    handle.stop(false).await;
+    let _ = std::fs::remove_file(&path);
+}
+
+#[actix_web::test]
+async fn start_rejects_an_invalid_config() {
+    let mut config = test_config();
+    config.server.workers = 0;
+    let error = CowchatServer::new(config).pool(memory_pool()).start().err().unwrap();
+    assert!(error.to_string().contains("server.workers must be at least 1"), "{}", error);
+}
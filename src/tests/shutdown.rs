@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use actix_web_actors::ws::CloseCode;
+use awc::ws::{Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+
+use crate::api::rooms::CloseAll;
+use crate::shutdown::{DrainReport, drain};
+use crate::tests::{test_app, test_config, test_state};
+
+#[actix_web::test]
+async fn draining_closes_chats_and_records_their_sessions() {
+    let state = test_state(&test_config());
+    let server = actix_test::start({
+        let state = state.clone();
+        move || test_app(&state)
+    });
+    let mut response = server.post("/cows/beckon").send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+    let chat_url = server.url(&format!("/cows/chat/{}", cow));
+    let (_, mut chat) = awc::Client::new().ws(chat_url.as_str()).connect().await.unwrap();
+    chat.send(Message::Text("Moo?".into())).await.unwrap();
+    assert!(matches!(chat.next().await, Some(Ok(Frame::Text(_)))));
+
+    let report = drain(state.chat_services(), Duration::from_secs(5)).await;
+    assert_eq!(report, DrainReport { closed: 1, flushed: 1, dropped: 0 });
+
+    // The client is told why, and the session was written before drain() returned.
+    loop {
+        match chat.next().await {
+            Some(Ok(Frame::Close(reason))) => {
+                assert_eq!(reason.unwrap().code, CloseCode::Away);
+                break;
+            },
+            Some(Ok(Frame::Ping(_))) => {},
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+    let mut response = server.get(format!("/cows/{}/sessions", cow)).send().await.unwrap();
+    let sessions: Value = response.json().await.unwrap();
+    assert_eq!(sessions["sessions"][0]["close_reason"], "server_shutdown");
+    assert_eq!(sessions["sessions"][0]["message_count"], 2);
+
+    // Nobody gets to start a new chat, but the rest of the API still answers.
+    let mut refused = server.get(format!("/cows/chat/{}", cow)).send().await.unwrap();
+    assert_eq!(refused.status(), 503);
+    let problem: Value = refused.json().await.unwrap();
+    assert_eq!(problem["code"], "shutting_down");
+    assert_eq!(server.get("/cows/count").send().await.unwrap().status(), 200);
+}
+
+// A chat can pass the draining check just before shutdown begins, and only join
+// its room after the registry was closed.
+#[actix_web::test]
+async fn chats_that_join_after_the_rooms_closed_are_ended_too() {
+    let state = test_state(&test_config());
+    let server = actix_test::start({
+        let state = state.clone();
+        move || test_app(&state)
+    });
+    let mut response = server.post("/cows/beckon").send_json(&json!({ "count": 1 })).await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+    // Only the rooms are closed, so the handler still lets the chat in.
+    assert_eq!(state.chat_services().rooms.send(CloseAll).await.unwrap(), 0);
+
+    let (_, mut chat) = awc::Client::new().ws(server.url(&format!("/cows/chat/{}", cow))).connect().await.unwrap();
+    loop {
+        match chat.next().await {
+            Some(Ok(Frame::Close(reason))) => {
+                assert_eq!(reason.unwrap().code, CloseCode::Away);
+                break;
+            },
+            Some(Ok(Frame::Ping(_))) => {},
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+    // The session is recorded before drain() gives up on it.
+    let report = drain(state.chat_services(), Duration::from_secs(5)).await;
+    assert_eq!(report.dropped, 0);
+    let mut response = server.get(format!("/cows/{}/sessions", cow)).send().await.unwrap();
+    let sessions: Value = response.json().await.unwrap();
+    assert_eq!(sessions["sessions"][0]["close_reason"], "server_shutdown");
+}
@@ -0,0 +1,135 @@
+use std::{
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use awc::{
+    Client, Connector,
+    ws::{Frame, Message},
+};
+use futures_util::{SinkExt, StreamExt};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    x509::{X509, X509Builder, X509NameBuilder},
+};
+use serde_json::{Value, json};
+
+use crate::CowchatServer;
+use crate::tests::{free_port, memory_pool, test_config};
+
+// A throwaway directory for the certificate, removed when the test is done.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cowchat-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Writes a fresh self-signed certificate and its key as cert.pem and key.pem,
+// and returns the certificate's SHA-256 fingerprint.
+fn self_signed(dir: &Path) -> Vec<u8> {
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap())
+        .unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost").unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(rand::random()).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert: X509 = builder.build();
+    std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+    cert.digest(MessageDigest::sha256()).unwrap().to_vec()
+}
+
+// The certificates are self-signed, so the clients don't check them. The
+// fingerprints say which one was served.
+fn trusting_connector() -> SslConnector {
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.build()
+}
+
+// The fingerprint of the certificate a new connection gets. Plain blocking
+// OpenSSL, because awc doesn't show the peer certificate.
+fn served_certificate(port: u16) -> Vec<u8> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let tls = trusting_connector().connect("localhost", stream).unwrap();
+    tls.ssl().peer_certificate().unwrap().digest(MessageDigest::sha256()).unwrap().to_vec()
+}
+
+async fn served_certificate_async(port: u16) -> Vec<u8> {
+    actix_web::rt::task::spawn_blocking(move || served_certificate(port)).await.unwrap()
+}
+
+#[actix_web::test]
+async fn tls_serves_requests_and_chats_and_reloads_its_certificate() {
+    let dir = TempDir::new("tls");
+    let first = self_signed(&dir.0);
+    let (port, http_port) = (free_port(), free_port());
+    let mut config = test_config();
+    config.server.host = "127.0.0.1".to_string();
+    config.server.port = port;
+    config.server.workers = 1;
+    config.tls.enabled = true;
+    config.tls.cert_path = dir.0.join("cert.pem").to_string_lossy().into_owned();
+    config.tls.key_path = dir.0.join("key.pem").to_string_lossy().into_owned();
+    config.tls.redirect_http_port = Some(http_port);
+
+    let server = CowchatServer::new(config).pool(memory_pool()).start().unwrap();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let client = Client::builder().connector(Connector::new().openssl(trusting_connector())).finish();
+
+    let mut response = client.post(format!("https://127.0.0.1:{}/cows/beckon", port))
+        .send_json(&json!({ "count": 1 })).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    let cow = body["cows"][0]["name"].as_str().unwrap().to_string();
+
+    let (_, mut chat) = client.ws(format!("wss://127.0.0.1:{}/cows/chat/{}", port, cow)).connect().await.unwrap();
+    chat.send(Message::Text("Moo?".into())).await.unwrap();
+    assert!(matches!(chat.next().await, Some(Ok(Frame::Text(_)))));
+    chat.send(Message::Close(None)).await.unwrap();
+
+    // Plain HTTP is sent over to HTTPS, path and all.
+    let plain = Client::builder().disable_redirects().finish();
+    let response = plain.get(format!("http://127.0.0.1:{}/cows/count?x=1", http_port)).send().await.unwrap();
+    assert_eq!(response.status(), 308);
+    assert_eq!(response.headers().get("location").unwrap().to_str().unwrap(),
+               format!("https://127.0.0.1:{}/cows/count?x=1", port));
+
+    // After SIGHUP, new connections get the new certificate.
+    assert_eq!(served_certificate_async(port).await, first);
+    let second = self_signed(&dir.0);
+    let status = std::process::Command::new("kill").args(["-HUP", &std::process::id().to_string()]).status();
+    assert!(status.unwrap().success());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while served_certificate_async(port).await != second {
+        assert!(Instant::now() < deadline, "The new certificate was never served");
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    }
+    handle.stop(false).await;
+}
@@ -0,0 +1,70 @@
+use actix::Actor;
+
+use crate::db::{
+    transcripts::{Flush, FlushStatus, MAX_FLUSH_RETRIES, Sender, TranscriptEvent, TranscriptWriter},
+    types::MyPool,
+};
+use crate::tests::{memory_pool, migrated};
+
+fn chat(key: u64) -> [TranscriptEvent; 3] {
+    [
+        TranscriptEvent::SessionStarted { key, cow: "Bessie".to_string(), started_at: 1, user_id: None },
+        TranscriptEvent::Message { key, sender: Sender::User, body: "Moo?".to_string(), sent_at: 2 },
+        TranscriptEvent::SessionEnded { key, ended_at: 3, duration_secs: 0, close_reason: "client_closed".to_string() },
+    ]
+}
+
+// Renaming the table makes every write fail until it is renamed back. The
+// statement goes through the writer's only connection, so it has to be back in
+// the pool before the writer needs it.
+fn rename(pool: &MyPool, from: &str, to: &str) {
+    pool.get().unwrap().execute(&format!("ALTER TABLE {} RENAME TO {}", from, to), []).unwrap();
+}
+
+fn count(pool: &MyPool, table: &str) -> i64 {
+    pool.get().unwrap().query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+}
+
+#[actix_web::test]
+async fn failed_writes_are_retried_in_order() {
+    let pool = migrated(memory_pool());
+    let writer = TranscriptWriter::new(pool.clone()).start();
+    rename(&pool, "chat_sessions", "chat_sessions_away");
+
+    for event in chat(1) {
+        writer.do_send(event);
+    }
+    assert_eq!(writer.send(Flush).await.unwrap(), FlushStatus { open: 1, lost: 0 });
+    rename(&pool, "chat_sessions_away", "chat_sessions");
+    assert_eq!(writer.send(Flush).await.unwrap(), FlushStatus { open: 0, lost: 0 });
+    assert_eq!((count(&pool, "chat_sessions"), count(&pool, "chat_messages")), (1, 1));
+}
+
+#[actix_web::test]
+async fn writes_that_keep_failing_are_given_up_on() {
+    let pool = migrated(memory_pool());
+    let writer = TranscriptWriter::new(pool.clone()).start();
+    rename(&pool, "chat_sessions", "chat_sessions_away");
+
+    for event in chat(1) {
+        writer.do_send(event);
+    }
+    // The writer's own timer may get in a retry of its own, so this can take fewer flushes.
+    let mut status = FlushStatus::default();
+    for _ in 0..=MAX_FLUSH_RETRIES {
+        status = writer.send(Flush).await.unwrap();
+        if status.lost > 0 {
+            break;
+        }
+        assert_eq!(status.open, 1);
+    }
+    assert_eq!(status, FlushStatus { open: 0, lost: 1 });
+
+    // Later chats are written as usual.
+    rename(&pool, "chat_sessions_away", "chat_sessions");
+    for event in chat(2) {
+        writer.do_send(event);
+    }
+    assert_eq!(writer.send(Flush).await.unwrap(), FlushStatus { open: 0, lost: 1 });
+    assert_eq!(count(&pool, "chat_sessions"), 1);
+}
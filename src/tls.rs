@@ -0,0 +1,110 @@
+use std::sync::{
+    Arc, RwLock,
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    http::header::LOCATION,
+};
+use anyhow::Context;
+use openssl::ssl::{
+    SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+};
+
+use crate::config::TlsConfig;
+
+// The certificate in use, shared between the TLS handshakes and the SIGHUP handler.
+// An RwLock lets any number of handshakes read it at once, while a reload has to
+// wait for exclusive access to swap it.
+#[derive(Clone)]
+pub(crate) struct CertStore {
+    current: Arc<RwLock<SslContext>>,
+    config: TlsConfig,
+}
+
+impl CertStore {
+    pub fn load(config: &TlsConfig) -> anyhow::Result<Self> {
+        let context = load_context(config)?;
+        Ok(Self { current: Arc::new(RwLock::new(context)), config: config.clone() })
+    }
+
+    // Re-reads the certificate and key from disk. New handshakes pick them up,
+    // established connections (including open chats) keep the one they started with.
+    // If the new files are broken, the old certificate stays in place.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let context = load_context(&self.config)?;
+        *self.current.write().unwrap() = context;
+        Ok(())
+    }
+
+    // The acceptor that bind_openssl() needs. It starts out with the certificate
+    // from startup, but swaps in whatever is current at the start of every
+    // handshake. OpenSSL calls the servername callback for every ClientHello,
+    // whether or not the client sent a server name.
+    pub fn acceptor(&self) -> anyhow::Result<SslAcceptorBuilder> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        configure_certificate(&mut builder, &self.config)?;
+        let current = self.current.clone();
+        builder.set_servername_callback(move |ssl, _| {
+            let context = current.read().unwrap();
+            ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(builder)
+    }
+}
+
+fn load_context(config: &TlsConfig) -> anyhow::Result<SslContext> {
+    let mut builder = SslContext::builder(SslMethod::tls())?;
+    configure_certificate(&mut builder, config)?;
+    Ok(builder.build())
+}
+
+// SslAcceptorBuilder derefs to SslContextBuilder, so this works for both.
+fn configure_certificate(builder: &mut openssl::ssl::SslContextBuilder, config: &TlsConfig) -> anyhow::Result<()> {
+    builder.set_certificate_chain_file(&config.cert_path)
+           .with_context(|| format!("Could not load TLS certificate {}", config.cert_path))?;
+    builder.set_private_key_file(&config.key_path, SslFiletype::PEM)
+           .with_context(|| format!("Could not load TLS key {}", config.key_path))?;
+    builder.check_private_key().context("TLS certificate and key don't match")?;
+    Ok(())
+}
+
+// Reloads the certificate whenever the process gets SIGHUP, e.g. after a
+// certificate renewal. Runs for as long as the server does.
+pub(crate) async fn reload_on_sighup(store: CertStore) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!("Could not listen for SIGHUP, certificate reload is disabled: {}", e);
+            return;
+        },
+    };
+    while hangups.recv().await.is_some() {
+        match store.reload() {
+            Ok(()) => log::info!("Reloaded TLS certificate {}", store.config.cert_path),
+            Err(e) => log::error!("Keeping the old TLS certificate, reload failed: {:#}", e),
+        }
+    }
+}
+
+// Default handler of the plain-HTTP redirect server. Sends every request to the
+// same path on the HTTPS port. 308 (unlike 301) makes clients repeat the request
+// with the same method and body.
+pub(crate) async fn redirect_to_https(req: HttpRequest, https_port: actix_web::web::Data<u16>) -> HttpResponse {
+    let info = req.connection_info();
+    // The Host header may carry the plain-HTTP port, which has to go. The digit
+    // check keeps bracketed IPv6 addresses like [::1] intact.
+    let host = match info.host().rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => info.host(),
+    };
+    let location = match **https_port {
+        443 => format!("https://{}{}", host, req.uri()),
+        port => format!("https://{}:{}{}", host, port, req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .insert_header((LOCATION, location))
+        .finish()
+}